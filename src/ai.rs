@@ -1,15 +1,92 @@
 use async_trait::async_trait;
-use std::error;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::batch::BatchMetrics;
+use crate::error::BertError;
+use crate::generation::{Generation, GenerationStream};
+use crate::params::GenerationParams;
 
 #[async_trait]
 pub trait AI: Send + Sync {
     async fn response(
         &self,
         context: String,
-        token_max_length: u16,
-        temperature: f32,
-        top_p: f32,
-        stop_sequence: Option<String>,
-    ) -> Result<String, Box<dyn error::Error>>;
+        params: GenerationParams,
+    ) -> Result<Generation, BertError>;
     fn name(&self) -> String;
+
+    /// Fails like `response` would before generating, without generating.
+    async fn check(&self, _context: String, _params: GenerationParams) -> Result<(), BertError> {
+        Ok(())
+    }
+
+    /// Identifies the weights behind `name`, where the backend knows them.
+    fn revision(&self) -> Option<String> {
+        None
+    }
+
+    /// Yields text as it is decoded. By default the whole text arrives at once.
+    async fn stream(
+        &self,
+        context: String,
+        params: GenerationParams,
+    ) -> Result<GenerationStream, BertError> {
+        let generation = self.response(context, params).await?;
+        let (deltas, receiver) = mpsc::unbounded_channel();
+        let (result, finished) = oneshot::channel();
+        if let Some(choice) = generation.choices.first() {
+            let _ = deltas.send(choice.text.clone());
+        }
+        let _ = result.send(Ok(generation));
+        Ok(GenerationStream::new(
+            self.name(),
+            self.revision(),
+            receiver,
+            finished,
+        ))
+    }
+
+    /// Batch sizes achieved by backends that batch concurrent requests.
+    fn batch_metrics(&self) -> Option<BatchMetrics> {
+        None
+    }
+
+    /// Whether a second copy of the weights, loaded to score tokens for
+    /// `logprobs`, is resident next to the model.
+    fn scorer_loaded(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::generation::{Choice, FinishReason, Usage};
+
+    /// Answers every request with its prompt.
+    pub(crate) struct Echo;
+
+    #[async_trait]
+    impl AI for Echo {
+        async fn response(
+            &self,
+            context: String,
+            _params: GenerationParams,
+        ) -> Result<Generation, BertError> {
+            Ok(Generation {
+                choices: vec![Choice {
+                    text: context,
+                    score: None,
+                    finish_reason: FinishReason::Length,
+                    logprobs: None,
+                }],
+                usage: Usage::default(),
+                logit_bias: Vec::new(),
+            })
+        }
+
+        fn name(&self) -> String {
+            "echo".to_string()
+        }
+    }
 }
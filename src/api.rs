@@ -1,20 +1,33 @@
 #[macro_use]
 extern crate rocket_include_static_resources;
 
-use bert_cli::{create_ai, Bert};
+use bert_cli::{
+    BatchConfig, Bert, BertError, Choice, ClassifyOptions, ClassifyResponse, FinishReason,
+    GenerationParams, ModelRegistry, ModelStatus, PreparedRequest, SentimentResponse, TokenBias,
+    Truncation, Usage,
+};
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::form::FromForm;
-use rocket::http::Header;
+use rocket::futures::stream::{BoxStream, StreamExt};
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::stream::{Event, EventStream, TextStream};
+use rocket::response::{self, Responder};
+use rocket::serde::json::{serde_json, Json};
 use rocket::State;
-use rocket::{get, post, routes, serde::json::Json};
+use rocket::{get, post, routes};
 use rocket::{Request, Response};
 use rocket_include_static_resources::{EtagIfNoneMatch, StaticContextManager, StaticResponse};
+use rocket_okapi::gen::OpenApiGenerator;
+use rocket_okapi::okapi::openapi3::Responses;
 use rocket_okapi::okapi::schemars;
 use rocket_okapi::okapi::schemars::JsonSchema;
+use rocket_okapi::response::OpenApiResponderInner;
 use rocket_okapi::settings::UrlObject;
+use rocket_okapi::util::add_schema_response;
 use rocket_okapi::{openapi, openapi_get_routes, rapidoc::*, swagger_ui::*};
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 
 mod config;
 use config::Config;
@@ -39,12 +52,24 @@ fn example_remove_input() -> Option<bool> {
     Some(true)
 }
 
-fn example_generated_text() -> &'static str {
-    "I want to generate a number sequence that includes the words \"Hello world\"."
+fn example_choices() -> Vec<Choice> {
+    vec![Choice {
+        text: "I want to generate a number sequence that includes the words \"Hello world\"."
+            .to_string(),
+        score: None,
+        finish_reason: FinishReason::Length,
+        logprobs: None,
+    }]
+}
+
+fn example_model() -> Option<&'static str> {
+    Some("gpt2")
 }
 
 #[derive(Serialize, Deserialize, JsonSchema, FromForm)]
 struct GenerationRequest {
+    #[schemars(example = "example_model")]
+    model: Option<String>,
     #[schemars(example = "example_context")]
     context: String,
     #[schemars(example = "example_top_p")]
@@ -55,15 +80,233 @@ struct GenerationRequest {
     response_length: u16,
     #[schemars(example = "example_remove_input")]
     remove_input: Option<bool>,
+    min_length: Option<u16>,
+    top_k: Option<u16>,
+    do_sample: Option<bool>,
+    early_stopping: Option<bool>,
+    num_beams: Option<u16>,
+    num_beam_groups: Option<u16>,
+    num_return_sequences: Option<u16>,
+    n: Option<u16>,
+    repetition_penalty: Option<f32>,
+    length_penalty: Option<f32>,
+    no_repeat_ngram_size: Option<u16>,
+    diversity_penalty: Option<f32>,
+    stop: Option<Vec<String>>,
+    seed: Option<u64>,
+    timeout_ms: Option<u64>,
+    logprobs: Option<u8>,
+    bad_words: Option<Vec<String>>,
+    force_words: Option<Vec<String>>,
+    /// Only bans, biases of -100 or less, are supported.
+    logit_bias: Option<BTreeMap<String, f32>>,
+    /// Replaces `response_length`, counting generated tokens only.
+    max_new_tokens: Option<u16>,
+    truncation: Option<Truncation>,
 }
 
-#[derive(Serialize, Deserialize, JsonSchema, FromForm)]
+impl GenerationRequest {
+    fn params(&self) -> GenerationParams {
+        GenerationParams {
+            max_length: match self.max_new_tokens {
+                Some(_) => None,
+                None => Some(self.response_length),
+            },
+            max_new_tokens: self.max_new_tokens,
+            truncation: self.truncation.unwrap_or_default(),
+            min_length: self.min_length,
+            do_sample: self.do_sample,
+            early_stopping: self.early_stopping,
+            num_beams: self.num_beams,
+            num_beam_groups: self.num_beam_groups,
+            num_return_sequences: self.num_return_sequences,
+            n: self.n,
+            temperature: Some(self.temp),
+            top_k: self.top_k,
+            top_p: Some(self.top_p),
+            repetition_penalty: self.repetition_penalty,
+            length_penalty: self.length_penalty,
+            no_repeat_ngram_size: self.no_repeat_ngram_size,
+            diversity_penalty: self.diversity_penalty,
+            stop: self.stop.clone().unwrap_or_default(),
+            seed: self.seed,
+            timeout_ms: self.timeout_ms,
+            echo: !self.remove_input.unwrap_or(true),
+            logprobs: self.logprobs,
+            bad_words: self.bad_words.clone().unwrap_or_default(),
+            force_words: self.force_words.clone().unwrap_or_default(),
+            logit_bias: self.logit_bias.clone().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
 struct GenerationResponse {
-    #[schemars(example = "example_generated_text")]
-    generated_text: String,
+    #[schemars(example = "example_choices")]
+    choices: Vec<Choice>,
+    model: String,
+    /// Identifies the model weights, `<repository>@<etag>`.
+    revision: Option<String>,
+    duration: u128,
+    /// The seed the request was generated with, if any.
+    seed: Option<u64>,
+    usage: Usage,
+    /// The biases applied, resolved to tokens.
+    logit_bias: Vec<TokenBias>,
+}
+
+fn example_sequences() -> Vec<&'static str> {
+    vec!["My order arrived broken and I would like a refund."]
+}
+
+fn example_labels() -> Vec<&'static str> {
+    vec!["billing", "shipping", "account"]
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, FromForm)]
+struct ClassifyRequest {
+    #[schemars(example = "example_sequences")]
+    sequences: Vec<String>,
+    #[schemars(example = "example_labels")]
+    labels: Vec<String>,
+    /// Scores every label on its own instead of having the labels share a total of 1.
+    multi_label: Option<bool>,
+    /// Turns a label into the hypothesis tested against a sequence, `{}` stands for the label.
+    hypothesis_template: Option<String>,
+}
+
+fn example_texts() -> Vec<&'static str> {
+    vec!["The tower is 324 metres tall, about the same height as an 81-storey building, and the tallest structure in Paris."]
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct SummarizeRequest {
+    #[schemars(example = "example_texts")]
+    texts: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct SummarizeResponse {
+    /// One summary per text, in order.
+    summaries: Vec<String>,
     duration: u128,
 }
 
+fn example_translation_model() -> Option<&'static str> {
+    Some("mbart")
+}
+
+fn example_source_lang() -> &'static str {
+    "de"
+}
+
+fn example_target_lang() -> &'static str {
+    "en"
+}
+
+fn example_translation_texts() -> Vec<&'static str> {
+    vec!["Das Wetter ist heute schön."]
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct TranslateRequest {
+    /// `mbart` (the default) or `m2m100`.
+    #[schemars(example = "example_translation_model")]
+    model: Option<String>,
+    /// Language code of the texts.
+    #[schemars(example = "example_source_lang")]
+    source_lang: String,
+    #[schemars(example = "example_target_lang")]
+    target_lang: String,
+    #[schemars(example = "example_translation_texts")]
+    texts: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct TranslateResponse {
+    /// One translation per text, in order.
+    translations: Vec<String>,
+    duration: u128,
+}
+
+fn example_feedback() -> Vec<&'static str> {
+    vec!["Support fixed my problem within minutes, thank you!"]
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct SentimentRequest {
+    #[schemars(example = "example_feedback")]
+    texts: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CompletionDelta {
+    text: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct ErrorResponse {
+    error: String,
+    message: String,
+}
+
+struct ApiError(BertError);
+
+impl ApiError {
+    fn status(&self) -> Status {
+        match self.0 {
+            BertError::InvalidParameters(_) => Status::BadRequest,
+            BertError::UnknownModel { .. } => Status::NotFound,
+            BertError::TokenizationOverflow { .. } => Status::PayloadTooLarge,
+            BertError::ModelLoad(_) => Status::ServiceUnavailable,
+            BertError::Inference(_) => Status::InternalServerError,
+            // Nobody is left to read it.
+            BertError::Cancelled => Status::new(499),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self.0 {
+            BertError::InvalidParameters(_) => "invalid_parameters",
+            BertError::UnknownModel { .. } => "unknown_model",
+            BertError::TokenizationOverflow { .. } => "tokenization_overflow",
+            BertError::ModelLoad(_) => "model_load",
+            BertError::Inference(_) => "inference",
+            BertError::Cancelled => "cancelled",
+        }
+    }
+
+    fn body(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: self.kind().to_string(),
+            message: self.0.to_string(),
+        }
+    }
+}
+
+impl From<BertError> for ApiError {
+    fn from(error: BertError) -> Self {
+        ApiError(error)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        (self.status(), Json(self.body())).respond_to(request)
+    }
+}
+
+impl OpenApiResponderInner for ApiError {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        let mut responses = Responses::default();
+        let schema = gen.json_schema::<ErrorResponse>();
+        for status in [400, 404, 413, 500, 503] {
+            add_schema_response(&mut responses, status, "application/json", schema.clone())?;
+        }
+        Ok(responses)
+    }
+}
+
 cached_static_response_handler! {
     259_200;
     "/index.js" => cached_indexjs => "indexjs",
@@ -78,39 +321,229 @@ fn default_index(
     static_resources.build(&etag_if_none_match, "index")
 }
 
+/// How long a completion may take before its response starts. From then on,
+/// whitespace is sent at this interval, which fails once the client has gone
+/// away and so cancels the generation.
+const KEEP_ALIVE: Duration = Duration::from_secs(1);
+
+/// A completion answered in full, or a response that keeps the connection alive
+/// with leading whitespace until the completion, or its error, follows.
+enum Completion<'r> {
+    Done(Result<Json<GenerationResponse>, ApiError>),
+    Pending(TextStream<BoxStream<'r, String>>),
+}
+
+impl<'r> Responder<'r, 'r> for Completion<'r> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'r> {
+        match self {
+            Completion::Done(result) => result.respond_to(request),
+            Completion::Pending(stream) => {
+                let mut response = stream.respond_to(request)?;
+                response.set_header(ContentType::JSON);
+                Ok(response)
+            }
+        }
+    }
+}
+
+impl<'r> OpenApiResponderInner for Completion<'r> {
+    fn responses(gen: &mut OpenApiGenerator) -> rocket_okapi::Result<Responses> {
+        <Result<Json<GenerationResponse>, ApiError>>::responses(gen)
+    }
+}
+
+async fn complete(
+    request: PreparedRequest<'_>,
+    start: Instant,
+) -> Result<GenerationResponse, ApiError> {
+    let response = request.generate().await?;
+    let duration = start.elapsed().as_millis();
+    Ok(GenerationResponse {
+        choices: response.choices,
+        model: response.model,
+        revision: response.revision,
+        duration,
+        seed: response.seed,
+        usage: response.usage,
+        logit_bias: response.logit_bias,
+    })
+}
+
+/// Invalid requests and prompts, unknown models and failed loads answer with
+/// their status before generation starts. Generations that take longer than a
+/// second then answer with status 200 and leading whitespace before the JSON
+/// body, which carries either the response or an error. Closing the connection
+/// cancels the generation.
 #[openapi(tag = "Generation")]
 #[get("/completion?<request..>")]
-async fn get_completion(
-    bert: &State<Bert>,
-    request: GenerationRequest,
-) -> Json<GenerationResponse> {
+async fn get_completion(bert: &State<Bert>, request: GenerationRequest) -> Completion<'_> {
     let start = Instant::now();
-    let response = bert
-        .generate(
+    let prepared = bert
+        .prepare(
+            request.model.as_deref(),
             request.context.to_string(),
-            request.response_length,
-            request.temp,
-            request.top_p,
-            None,
+            request.params(),
         )
-        .await
-        .unwrap();
-    let duration = start.elapsed().as_millis();
-    Json(GenerationResponse {
-        generated_text: response.text,
-        duration,
-    })
+        .await;
+    let mut generation = match prepared {
+        Ok(prepared) => Box::pin(complete(prepared, start)),
+        Err(err) => return Completion::Done(Err(err.into())),
+    };
+    tokio::select! {
+        result = &mut generation => return Completion::Done(result.map(Json)),
+        _ = tokio::time::sleep(KEEP_ALIVE) => {}
+    }
+    let stream = TextStream! {
+        loop {
+            let result = tokio::select! {
+                result = &mut generation => Some(result),
+                _ = tokio::time::sleep(KEEP_ALIVE) => None,
+            };
+            match result {
+                Some(result) => {
+                    let body = match result {
+                        Ok(response) => serde_json::to_string(&response),
+                        Err(err) => serde_json::to_string(&err.body()),
+                    };
+                    yield body.unwrap_or_default();
+                    break;
+                }
+                None => yield " ".to_string(),
+            }
+        }
+    };
+    Completion::Pending(TextStream(stream.0.boxed()))
 }
 
 #[openapi(tag = "Generation")]
 #[post("/completion", data = "<request>")]
-async fn post_completion(
-    bert: &State<Bert>,
-    request: Json<GenerationRequest>,
-) -> Json<GenerationResponse> {
+async fn post_completion(bert: &State<Bert>, request: Json<GenerationRequest>) -> Completion<'_> {
     get_completion(bert, request.into_inner()).await
 }
 
+/// Server-Sent Events variant of `/completion`. `delta` events carry text as it is
+/// generated and are followed by a `done` event with the full response, or by an
+/// `error` event.
+#[get("/completion/stream?<request..>")]
+fn stream_completion(bert: &State<Bert>, request: GenerationRequest) -> EventStream![Event + '_] {
+    EventStream! {
+        let start = Instant::now();
+        let stream = bert
+            .stream(request.model.as_deref(), request.context.to_string(), request.params())
+            .await;
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                yield Event::json(&ApiError(err).body()).event("error");
+                return;
+            }
+        };
+        let model = stream.model().to_string();
+        let revision = stream.revision().map(str::to_string);
+        let seed = stream.seed();
+        while let Some(text) = stream.next_delta().await {
+            yield Event::json(&CompletionDelta { text }).event("delta");
+        }
+        match stream.finish().await {
+            Ok(generation) => {
+                let response = GenerationResponse {
+                    choices: generation.choices,
+                    model,
+                    revision,
+                    duration: start.elapsed().as_millis(),
+                    seed,
+                    usage: generation.usage,
+                    logit_bias: generation.logit_bias,
+                };
+                yield Event::json(&response).event("done");
+            }
+            Err(err) => {
+                yield Event::json(&ApiError(err).body()).event("error");
+            }
+        }
+    }
+}
+
+/// Classifies every sequence into the labels, in one call for the whole batch.
+#[openapi(tag = "Classification")]
+#[get("/classify?<request..>")]
+async fn get_classify(
+    bert: &State<Bert>,
+    request: ClassifyRequest,
+) -> Result<Json<Vec<ClassifyResponse>>, ApiError> {
+    let options = ClassifyOptions {
+        multi_label: request.multi_label.unwrap_or(false),
+        hypothesis_template: request.hypothesis_template,
+    };
+    let responses = bert
+        .classify(request.sequences, request.labels, options)
+        .await?;
+    Ok(Json(responses))
+}
+
+#[openapi(tag = "Classification")]
+#[post("/classify", data = "<request>")]
+async fn post_classify(
+    bert: &State<Bert>,
+    request: Json<ClassifyRequest>,
+) -> Result<Json<Vec<ClassifyResponse>>, ApiError> {
+    get_classify(bert, request.into_inner()).await
+}
+
+/// Summarizes every text with BART-CNN, using beam search.
+#[openapi(tag = "Summarization")]
+#[post("/summarize", data = "<request>")]
+async fn post_summarize(
+    bert: &State<Bert>,
+    request: Json<SummarizeRequest>,
+) -> Result<Json<SummarizeResponse>, ApiError> {
+    let start = Instant::now();
+    let summaries = bert.summarize(request.into_inner().texts).await?;
+    Ok(Json(SummarizeResponse {
+        summaries,
+        duration: start.elapsed().as_millis(),
+    }))
+}
+
+/// Translates every text from `source_lang` into `target_lang`.
+#[openapi(tag = "Translation")]
+#[post("/translate", data = "<request>")]
+async fn post_translate(
+    bert: &State<Bert>,
+    request: Json<TranslateRequest>,
+) -> Result<Json<TranslateResponse>, ApiError> {
+    let start = Instant::now();
+    let request = request.into_inner();
+    let translations = bert
+        .translate(
+            request.model.as_deref(),
+            request.texts,
+            request.source_lang,
+            request.target_lang,
+        )
+        .await?;
+    Ok(Json(TranslateResponse {
+        translations,
+        duration: start.elapsed().as_millis(),
+    }))
+}
+
+/// Scores every text as positive or negative.
+#[openapi(tag = "Sentiment")]
+#[post("/sentiment", data = "<request>")]
+async fn post_sentiment(
+    bert: &State<Bert>,
+    request: Json<SentimentRequest>,
+) -> Result<Json<Vec<SentimentResponse>>, ApiError> {
+    Ok(Json(bert.sentiment(request.into_inner().texts).await?))
+}
+
+#[openapi(tag = "Models")]
+#[get("/models")]
+async fn get_models(bert: &State<Bert>) -> Json<Vec<ModelStatus>> {
+    Json(bert.models().await)
+}
+
 pub struct CORS;
 
 #[rocket::async_trait]
@@ -140,16 +573,30 @@ async fn main() {
     let figment = rocket.figment();
     let config: Config = figment.extract().expect("config");
 
-    let bert = Bert {
-        ai: create_ai(
-            config.model,
-            config.token_max_length,
-            config.temperature,
-            config.top_p,
-        ),
+    let mut bert = match Bert::with_models(
+        ModelRegistry::default(),
+        &config.models,
+        &config.model,
+        config.token_max_length,
+        config.temperature,
+        config.top_p,
+    ) {
+        Ok(bert) => bert,
+        Err(err) => {
+            println!("Models could not be configured: {}", err);
+            return;
+        }
     };
-
-    println!("Model {} loaded.", bert.ai.name());
+    bert.set_memory_budget(config.memory_budget_mb);
+    let mut batch = BatchConfig::default();
+    if let Some(max_batch_size) = config.max_batch_size {
+        batch.max_batch_size = max_batch_size;
+    }
+    if let Some(max_batch_wait_ms) = config.max_batch_wait_ms {
+        batch.max_wait = Duration::from_millis(max_batch_wait_ms);
+    }
+    bert.set_batching(batch);
+    bert.set_timeout(config.timeout_ms);
     let launch_result = rocket
         .attach(static_resources_initializer!(
             "indexjs" => "static/index.js",
@@ -160,8 +607,18 @@ async fn main() {
         .mount("/", routes![cached_indexjs, cached_indexcss, default_index])
         .mount(
             "/api/",
-            openapi_get_routes![get_completion, post_completion],
+            openapi_get_routes![
+                get_completion,
+                post_completion,
+                get_classify,
+                post_classify,
+                post_summarize,
+                post_translate,
+                post_sentiment,
+                get_models
+            ],
         )
+        .mount("/api/", routes![stream_completion])
         .mount(
             "/swagger-ui/",
             make_swagger_ui(&SwaggerUIConfig {
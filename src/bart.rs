@@ -1,20 +1,29 @@
-use async_trait::async_trait;
 use rust_bert::bart::{
-    BartConfigResources, BartGenerator, BartMergesResources, BartModelResources, BartVocabResources,
+    BartConfig, BartConfigResources, BartGenerator, BartMergesResources, BartModelResources,
+    BartVocabResources,
 };
-use rust_bert::pipelines::generation_utils::{GenerateConfig, GenerateOptions, LanguageGenerator};
-use rust_bert::resources::RemoteResource;
-use std::error;
+use rust_bert::pipelines::common::TokenizerOption;
+use rust_bert::pipelines::generation_utils::{
+    GenerateConfig, GenerateOptions, GeneratedIndicesOutput, LanguageGenerator,
+};
+use rust_bert::resources::{RemoteResource, ResourceProvider};
+use rust_bert::Config;
 use tch::Device;
 
-use crate::ai::AI;
+use crate::error::BertError;
+use crate::generation::{revision, Defaults, Generator};
 
 pub struct Bart {
     model: BartGenerator,
+    vocab_size: i64,
+    eos_token_id: i64,
+    max_positions: i64,
+    defaults: Defaults,
+    revision: String,
 }
 
 impl Bart {
-    pub fn new(token_max_length: u16, temperature: f32, top_p: f32) -> Self {
+    pub fn new(token_max_length: u16, temperature: f32, top_p: f32) -> Result<Self, BertError> {
         let config_resource = Box::new(RemoteResource::from_pretrained(
             BartConfigResources::BART_CNN,
         ));
@@ -28,6 +37,8 @@ impl Bart {
             BartModelResources::BART_CNN,
         ));
         let device = Device::cuda_if_available();
+        let config = BartConfig::from_file(config_resource.get_local_path()?);
+        let revision = revision(&model_resource, &model_resource.get_local_path()?);
         let generate_config = GenerateConfig {
             model_resource,
             config_resource,
@@ -37,74 +48,113 @@ impl Bart {
             max_length: token_max_length.into(),
             do_sample: true,
             early_stopping: false,
-            repetition_penalty: 1.0,
+            repetition_penalty: 1.1,
             top_p: top_p.into(),
-            top_k: 55,
+            top_k: 10,
             temperature: temperature.into(),
             device,
             ..Default::default()
         };
-        let model = std::thread::spawn(move || BartGenerator::new(generate_config).unwrap())
-            .join()
-            .expect("Thread panicked");
+        let defaults = Defaults::from(&generate_config);
+        let model = BartGenerator::new(generate_config)?;
 
-        Bart { model }
+        Ok(Bart {
+            model,
+            vocab_size: config.vocab_size,
+            eos_token_id: config.eos_token_id.unwrap_or(2),
+            max_positions: config.max_position_embeddings,
+            defaults,
+            revision,
+        })
     }
 }
 
-unsafe impl Send for Bart {}
+impl Generator for Bart {
+    fn generate_indices(
+        &self,
+        prompts: &[String],
+        options: GenerateOptions,
+    ) -> Vec<GeneratedIndicesOutput> {
+        self.model.generate_indices(Some(prompts), Some(options))
+    }
 
-unsafe impl Sync for Bart {}
+    fn tokenizer(&self) -> &TokenizerOption {
+        self.model.get_tokenizer()
+    }
 
-#[async_trait]
-impl AI for Bart {
-    async fn response(
-        &self,
-        context: String,
-        token_max_length: u16,
-        temperature: f32,
-        top_p: f32,
-        _stop_sequence: Option<String>,
-    ) -> Result<String, Box<dyn error::Error>> {
-        let generate_options = GenerateOptions {
-            max_length: Some(token_max_length.into()),
-            do_sample: Some(true),
-            early_stopping: Some(false),
-            repetition_penalty: Some(1.1),
-            temperature: Some(temperature.into()),
-            top_p: Some(top_p.into()),
-            top_k: Some(10),
-            ..Default::default()
-        };
+    fn vocab_size(&self) -> i64 {
+        self.vocab_size
+    }
+
+    fn eos_token_id(&self) -> i64 {
+        self.eos_token_id
+    }
+
+    fn max_positions(&self) -> i64 {
+        self.max_positions
+    }
 
-        let output = self
-            .model
-            .generate(Some(&[context.to_string()]), Some(generate_options));
-        let response = output[0].text.to_string();
-        let response: String = response.replace(context.as_str(), "");
-        Ok(response)
+    fn defaults(&self) -> &Defaults {
+        &self.defaults
     }
 
-    fn name(&self) -> String {
-        "bart".to_string()
+    fn is_encoder_decoder(&self) -> bool {
+        true
+    }
+
+    fn revision(&self) -> &str {
+        &self.revision
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ai::AI;
+    use crate::batch::BatchConfig;
+    use crate::params::GenerationParams;
+    use crate::worker::Worker;
 
     #[tokio::test]
     async fn test_response() {
-        let ai = Bart::new(42, 1.1, 0.9);
+        let ai = Worker::spawn("bart", BatchConfig::default(), || Bart::new(42, 1.1, 0.9)).unwrap();
+        let context = "Lots of Tesla cars to deliver before year end! Your support in taking delivery is much appreciated.".to_string();
+        let params = GenerationParams {
+            max_length: Some(42),
+            temperature: Some(1.1),
+            top_p: Some(0.9),
+            ..Default::default()
+        };
+        let output = ai
+            .response(context.to_string(), params)
+            .await
+            .unwrap()
+            .choices
+            .remove(0);
+        println!("{}", output.text);
+        assert_ne!(output.text, context);
+        assert_ne!(output.text.len(), 0);
+        assert!(output.text.len() > 10);
+    }
+
+    #[tokio::test]
+    async fn test_constraints() {
+        let ai = Worker::spawn("bart", BatchConfig::default(), || Bart::new(42, 1.1, 0.9)).unwrap();
         let context = "Lots of Tesla cars to deliver before year end! Your support in taking delivery is much appreciated.".to_string();
+        let params = GenerationParams {
+            max_length: Some(42),
+            bad_words: vec!["Tesla".to_string()],
+            force_words: vec!["electric".to_string()],
+            ..Default::default()
+        };
         let output = ai
-            .response(context.to_string(), 42, 1.1, 0.9, None)
+            .response(context, params)
             .await
-            .unwrap();
-        println!("{}", output);
-        assert_ne!(output, context);
-        assert_ne!(output.len(), 0);
-        assert!(output.len() > 10);
+            .unwrap()
+            .choices
+            .remove(0);
+        println!("{}", output.text);
+        assert!(!output.text.contains("Tesla"));
+        assert!(output.text.contains("electric"));
     }
 }
@@ -0,0 +1,138 @@
+use rocket_okapi::okapi::schemars;
+use rocket_okapi::okapi::schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+/// How a model groups concurrent requests into one generation batch.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    pub max_batch_size: usize,
+    /// How long the first request of a batch waits for others to join it.
+    pub max_wait: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_batch_size: 8,
+            max_wait: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Batch sizes achieved since the model was loaded.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct BatchMetrics {
+    pub batches: u64,
+    pub requests: u64,
+    pub max_batch_size: usize,
+    /// `sizes[n]` is the number of batches that held `n + 1` requests.
+    pub sizes: Vec<u64>,
+}
+
+impl BatchMetrics {
+    pub(crate) fn record(&mut self, batch_size: usize) {
+        if batch_size == 0 {
+            return;
+        }
+        self.batches += 1;
+        self.requests += batch_size as u64;
+        self.max_batch_size = self.max_batch_size.max(batch_size);
+        if self.sizes.len() < batch_size {
+            self.sizes.resize(batch_size, 0);
+        }
+        self.sizes[batch_size - 1] += 1;
+    }
+}
+
+/// Groups items from a channel into batches of compatible items. Items that do
+/// not fit the current batch are kept, in order, for the next one.
+pub(crate) struct Batcher<T> {
+    receiver: Receiver<T>,
+    queue: VecDeque<T>,
+    config: BatchConfig,
+}
+
+impl<T> Batcher<T> {
+    pub fn new(receiver: Receiver<T>, config: BatchConfig) -> Self {
+        Batcher {
+            receiver,
+            queue: VecDeque::new(),
+            config,
+        }
+    }
+
+    /// Blocks until an item arrives, then gathers items `compatible` with it until
+    /// the batch is full or `max_wait` has passed. Returns `None` once the channel
+    /// is closed and drained.
+    pub fn next_batch<F>(&mut self, compatible: F) -> Option<Vec<T>>
+    where
+        F: Fn(&T, &T) -> bool,
+    {
+        let first = match self.queue.pop_front() {
+            Some(item) => item,
+            None => self.receiver.recv().ok()?,
+        };
+        let max_batch_size = self.config.max_batch_size.max(1);
+        let mut batch = vec![first];
+
+        let mut index = 0;
+        while index < self.queue.len() && batch.len() < max_batch_size {
+            if compatible(&batch[0], &self.queue[index]) {
+                batch.extend(self.queue.remove(index));
+            } else {
+                index += 1;
+            }
+        }
+
+        let deadline = Instant::now() + self.config.max_wait;
+        while batch.len() < max_batch_size {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.receiver.recv_timeout(timeout) {
+                Ok(item) if compatible(&batch[0], &item) => batch.push(item),
+                Ok(item) => self.queue.push_back(item),
+                Err(_) => break,
+            }
+        }
+        Some(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_next_batch() {
+        let (sender, receiver) = mpsc::channel();
+        let config = BatchConfig {
+            max_batch_size: 3,
+            max_wait: Duration::from_millis(1),
+        };
+        let mut batcher = Batcher::new(receiver, config);
+        for item in [1, 3, 2, 5, 7, 9, 4] {
+            sender.send(item).unwrap();
+        }
+        drop(sender);
+        let same_parity = |a: &i32, b: &i32| a % 2 == b % 2;
+        assert_eq!(batcher.next_batch(same_parity), Some(vec![1, 3, 5]));
+        assert_eq!(batcher.next_batch(same_parity), Some(vec![2, 4]));
+        assert_eq!(batcher.next_batch(same_parity), Some(vec![7, 9]));
+        assert_eq!(batcher.next_batch(same_parity), None);
+    }
+
+    #[test]
+    fn test_record() {
+        let mut metrics = BatchMetrics::default();
+        metrics.record(1);
+        metrics.record(3);
+        metrics.record(3);
+        assert_eq!(metrics.batches, 3);
+        assert_eq!(metrics.requests, 7);
+        assert_eq!(metrics.max_batch_size, 3);
+        assert_eq!(metrics.sizes, vec![1, 0, 2]);
+    }
+}
@@ -0,0 +1,59 @@
+use rocket_okapi::okapi::schemars;
+use rocket_okapi::okapi::schemars::JsonSchema;
+use rust_bert::pipelines::common::TokenizerOption;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::error::BertError;
+
+/// Largest bias, in either direction, a token may be given. rust-bert can only
+/// mask scores, so the only bias applied is `-MAX_BIAS`, which bans the token.
+pub const MAX_BIAS: f32 = 100.0;
+
+/// A bias added to the logit of a token at every decoding step.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct TokenBias {
+    pub token_id: i64,
+    pub token: String,
+    pub bias: f32,
+}
+
+/// Resolves the keys of `logit_bias` against the model's vocabulary. A key is
+/// either a token id or text, and every token of the text gets the bias.
+/// Biases given to the same token more than once add up.
+pub(crate) fn resolve(
+    tokenizer: &TokenizerOption,
+    vocab_size: i64,
+    logit_bias: &BTreeMap<String, f32>,
+) -> Result<Vec<TokenBias>, BertError> {
+    let mut biases: BTreeMap<i64, f32> = BTreeMap::new();
+    for (key, bias) in logit_bias {
+        let token_ids = match key.parse::<i64>() {
+            Ok(token_id) if (0..vocab_size).contains(&token_id) => vec![token_id],
+            Ok(token_id) => {
+                return Err(BertError::InvalidParameters(format!(
+                    "logit_bias token id {} is not in the vocabulary of {} tokens",
+                    token_id, vocab_size
+                )))
+            }
+            Err(_) => tokenizer.convert_tokens_to_ids(&tokenizer.tokenize(key)),
+        };
+        if token_ids.is_empty() {
+            return Err(BertError::InvalidParameters(format!(
+                "logit_bias key {:?} has no tokens",
+                key
+            )));
+        }
+        for token_id in token_ids {
+            *biases.entry(token_id).or_insert(0.0) += bias;
+        }
+    }
+    Ok(biases
+        .into_iter()
+        .map(|(token_id, bias)| TokenBias {
+            token_id,
+            token: tokenizer.decode(&[token_id], false, false),
+            bias,
+        })
+        .collect())
+}
@@ -1,5 +1,9 @@
-use bert_cli::{create_ai, Bert};
+use bert_cli::{
+    create_ai, Bert, BertError, Classifier, ClassifyOptions, GenerationParams, Logprob,
+    ModelRegistry, Polarity, SentimentAnalyzer, Summarizer, Translator, Truncation, Usage,
+};
 
+use std::io::{self, Write};
 use structopt::StructOpt;
 
 #[derive(Debug, Clone, StructOpt)]
@@ -8,22 +12,125 @@ enum Command {
         #[structopt(short = "length", long = "token_max_length", default_value = "200")]
         token_max_length: u16,
 
+        #[structopt(long = "min_length")]
+        min_length: Option<u16>,
+
+        /// Generates this many tokens after the prompt, replacing --token_max_length
+        #[structopt(long = "max_new_tokens")]
+        max_new_tokens: Option<u16>,
+
+        /// Trims prompts too long for the model: error, left or right
+        #[structopt(long = "truncation", default_value = "error")]
+        truncation: Truncation,
+
         #[structopt(short = "temp", long = "temperature", default_value = "0.9")]
         temperature: f32,
 
         #[structopt(short = "p", long = "top_p", default_value = "0.9")]
         top_p: f32,
 
-        #[structopt(short = "stop", long = "stop_sequence")]
-        stop_sequence: Option<String>,
+        #[structopt(short = "k", long = "top_k")]
+        top_k: Option<u16>,
+
+        #[structopt(long = "do_sample")]
+        do_sample: Option<bool>,
+
+        #[structopt(long = "early_stopping")]
+        early_stopping: Option<bool>,
+
+        #[structopt(long = "num_beams")]
+        num_beams: Option<u16>,
+
+        #[structopt(long = "num_beam_groups")]
+        num_beam_groups: Option<u16>,
+
+        #[structopt(long = "num_return_sequences")]
+        num_return_sequences: Option<u16>,
+
+        #[structopt(short = "n", long = "n")]
+        n: Option<u16>,
+
+        #[structopt(long = "seed")]
+        seed: Option<u64>,
+
+        #[structopt(long = "timeout_ms")]
+        timeout_ms: Option<u64>,
+
+        /// Prints every token's log-probability and this many alternatives
+        #[structopt(long = "logprobs")]
+        logprobs: Option<u8>,
+
+        #[structopt(long = "repetition_penalty")]
+        repetition_penalty: Option<f32>,
+
+        #[structopt(long = "length_penalty")]
+        length_penalty: Option<f32>,
+
+        #[structopt(long = "no_repeat_ngram_size")]
+        no_repeat_ngram_size: Option<u16>,
+
+        #[structopt(long = "diversity_penalty")]
+        diversity_penalty: Option<f32>,
+
+        #[structopt(short = "stop", long = "stop_sequence", number_of_values = 1)]
+        stop_sequence: Vec<String>,
+
+        /// Never generates this word, may be repeated
+        #[structopt(long = "bad_word", number_of_values = 1)]
+        bad_word: Vec<String>,
+
+        /// Generates this phrase somewhere in the text, may be repeated
+        #[structopt(long = "force_word", number_of_values = 1)]
+        force_word: Vec<String>,
+
+        /// Bans TOKEN, a token id or text, as TOKEN=-100; other biases are rejected
+        #[structopt(long = "logit_bias", number_of_values = 1, parse(try_from_str = parse_bias))]
+        logit_bias: Vec<(String, f32)>,
 
         context: String,
     },
+    /// Classifies a sequence into labels without training examples
     Classify {
+        /// Scores every label on its own instead of sharing a total of 1
+        #[structopt(long = "multi_label")]
+        multi_label: bool,
+
+        /// Hypothesis tested for every label, with {} for the label
+        #[structopt(long = "hypothesis_template")]
+        hypothesis_template: Option<String>,
+
         #[structopt(required = true)]
         labels: Vec<String>,
         sequence: String,
     },
+    /// Summarizes every text with BART fine-tuned on CNN/DailyMail
+    Summarize {
+        #[structopt(required = true)]
+        texts: Vec<String>,
+    },
+    /// Translates every text between two languages with mBART-50 or M2M100
+    Translate {
+        /// Language code of the texts, like de
+        #[structopt(long = "from")]
+        from: String,
+
+        /// Language code to translate into, like en
+        #[structopt(long = "to")]
+        to: String,
+
+        #[structopt(long = "model", default_value = "mbart")]
+        model: String,
+
+        #[structopt(required = true)]
+        texts: Vec<String>,
+    },
+    /// Scores every text as positive or negative
+    Sentiment {
+        #[structopt(required = true)]
+        texts: Vec<String>,
+    },
+    /// Lists the available models
+    Models,
 }
 
 #[derive(Debug, StructOpt)]
@@ -35,40 +142,265 @@ struct Opt {
     #[structopt(short = "m", long = "model", default_value = "gpt2")]
     model: String,
 
+    /// Prints token counts to stderr
+    #[structopt(long = "usage", global = true)]
+    usage: bool,
+
+    /// Prints the prompt before the generated text
+    #[structopt(long = "echo", global = true)]
+    echo: bool,
+
     context: Option<String>,
 }
 
-#[tokio::main]
-async fn main() {
-    let opt = Opt::from_args();
-    let ai = create_ai(opt.model, 200, 1.1, 0.9);
-    let gpt = Bert { ai };
+fn parse_bias(value: &str) -> Result<(String, f32), String> {
+    let (token, bias) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected TOKEN=BIAS, got {:?}", value))?;
+    let bias = bias
+        .parse()
+        .map_err(|err| format!("invalid bias: {}", err))?;
+    Ok((token.to_string(), bias))
+}
+
+fn exit_code(error: &BertError) -> i32 {
+    match error {
+        BertError::InvalidParameters(_) => 2,
+        BertError::UnknownModel { .. } => 3,
+        BertError::ModelLoad(_) => 4,
+        BertError::TokenizationOverflow { .. } => 5,
+        BertError::Inference(_) => 6,
+        BertError::Cancelled => 7,
+    }
+}
+
+/// Prints text as it is generated, or all at once for beam search, several completions
+/// and logprobs.
+async fn print_generation(
+    gpt: &Bert,
+    context: String,
+    params: GenerationParams,
+    usage: bool,
+) -> Result<(), BertError> {
+    if params.beam_search() || params.choices() > 1 || params.logprobs.is_some() {
+        let response = gpt.generate(None, context, params).await?;
+        let numbered = response.choices.len() > 1;
+        for (index, choice) in response.choices.iter().enumerate() {
+            if numbered {
+                match choice.score {
+                    Some(score) => println!("[{}] score {:.3}", index + 1, score),
+                    None => println!("[{}]", index + 1),
+                }
+            }
+            println!("{}", choice.text);
+            for logprob in choice.logprobs.iter().flatten() {
+                print_logprob(logprob);
+            }
+        }
+        print_seed(response.seed);
+        if usage {
+            print_usage(&response.usage);
+        }
+        return Ok(());
+    }
+    let mut stream = gpt.stream(None, context, params).await?;
+    let seed = stream.seed();
+    let mut stdout = io::stdout();
+    while let Some(delta) = stream.next_delta().await {
+        print!("{}", delta);
+        let _ = stdout.flush();
+    }
+    let generation = stream.finish().await?;
+    println!();
+    print_seed(seed);
+    if usage {
+        print_usage(&generation.usage);
+    }
+    Ok(())
+}
+
+fn print_logprob(logprob: &Logprob) {
+    let alternatives: Vec<String> = logprob
+        .top_logprobs
+        .iter()
+        .map(|top| format!("{:?} {:.3}", top.token, top.logprob))
+        .collect();
+    println!(
+        "{:>10} {:<16?} {:.3}  {}",
+        logprob.token_id,
+        logprob.token,
+        logprob.logprob,
+        alternatives.join(", ")
+    );
+}
+
+/// Prints the seed to stderr, so that the output can be reproduced with `--seed`.
+fn print_seed(seed: Option<u64>) {
+    if let Some(seed) = seed {
+        eprintln!("seed: {}", seed);
+    }
+}
+
+fn print_usage(usage: &Usage) {
+    eprintln!(
+        "prompt_tokens: {}, completion_tokens: {}, total_tokens: {}, dropped_tokens: {}",
+        usage.prompt_tokens, usage.completion_tokens, usage.total_tokens, usage.dropped_tokens
+    );
+}
+
+/// Runs a pipeline that loads and infers synchronously off the async runtime.
+async fn blocking<T, F>(job: F) -> Result<T, BertError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, BertError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(job)
+        .await
+        .map_err(|err| BertError::Inference(err.to_string()))?
+}
+
+async fn run(opt: Opt) -> Result<(), BertError> {
     match opt.command {
+        Some(Command::Models) => {
+            for entry in ModelRegistry::default().models() {
+                println!(
+                    "{:<12} {:<20} {}",
+                    entry.name,
+                    entry.aliases.join(", "),
+                    entry.description
+                );
+            }
+        }
+        Some(Command::Classify {
+            multi_label,
+            hypothesis_template,
+            labels,
+            sequence,
+        }) => {
+            let options = ClassifyOptions {
+                multi_label,
+                hypothesis_template,
+            };
+            options.validate(&labels)?;
+            let responses =
+                blocking(move || Classifier::new()?.classify(&[sequence], &labels, &options))
+                    .await?;
+            for response in responses {
+                for (label, score) in response.labels.iter().zip(&response.scores) {
+                    println!("{:<20} {:.4}", label, score);
+                }
+            }
+        }
+        Some(Command::Summarize { texts }) => {
+            let summaries = blocking(move || Summarizer::new()?.summarize(&texts)).await?;
+            for summary in summaries {
+                println!("{}", summary);
+            }
+        }
+        Some(Command::Translate {
+            from,
+            to,
+            model,
+            texts,
+        }) => {
+            let model = ModelRegistry::default().resolve(&model)?.name.clone();
+            Translator::check(&model, &from, &to)?;
+            let translations =
+                blocking(move || Translator::new(&model)?.translate(&texts, &from, &to)).await?;
+            for translation in translations {
+                println!("{}", translation);
+            }
+        }
+        Some(Command::Sentiment { texts }) => {
+            let responses = blocking(move || SentimentAnalyzer::new()?.analyze(&texts)).await?;
+            for response in responses {
+                let polarity = match response.polarity {
+                    Polarity::Positive => "positive",
+                    Polarity::Negative => "negative",
+                };
+                println!("{:<10} {:.4} {}", polarity, response.score, response.text);
+            }
+        }
         Some(Command::Generate {
             token_max_length,
+            min_length,
+            max_new_tokens,
+            truncation,
             temperature,
             top_p,
+            top_k,
+            do_sample,
+            early_stopping,
+            num_beams,
+            num_beam_groups,
+            num_return_sequences,
+            n,
+            seed,
+            timeout_ms,
+            logprobs,
+            repetition_penalty,
+            length_penalty,
+            no_repeat_ngram_size,
+            diversity_penalty,
             stop_sequence,
+            bad_word,
+            force_word,
+            logit_bias,
             context,
         }) => {
-            let response = gpt
-                .generate(context, token_max_length, temperature, top_p, stop_sequence)
-                .await
-                .unwrap();
-            println!("{}", response.text);
-        }
-        Some(Command::Classify {
-            labels: _,
-            sequence: _,
-        }) => {
-            unimplemented!("Not implemented yet!");
+            let params = GenerationParams {
+                max_length: match max_new_tokens {
+                    Some(_) => None,
+                    None => Some(token_max_length),
+                },
+                max_new_tokens,
+                truncation,
+                min_length,
+                do_sample,
+                early_stopping,
+                num_beams,
+                num_beam_groups,
+                num_return_sequences,
+                n,
+                temperature: Some(temperature),
+                top_k,
+                top_p: Some(top_p),
+                repetition_penalty,
+                length_penalty,
+                no_repeat_ngram_size,
+                diversity_penalty,
+                stop: stop_sequence,
+                seed,
+                timeout_ms,
+                echo: opt.echo,
+                logprobs,
+                bad_words: bad_word,
+                force_words: force_word,
+                logit_bias: logit_bias.into_iter().collect(),
+            };
+            let gpt = Bert::new(create_ai(opt.model, 200, 1.1, 0.9)?);
+            print_generation(&gpt, context, params, opt.usage).await?;
         }
         None => {
-            let response = gpt
-                .generate(opt.context.unwrap_or_default(), 200, 0.9, 0.9, None)
-                .await
-                .unwrap();
-            println!("{}", response.text);
+            let params = GenerationParams {
+                max_length: Some(200),
+                temperature: Some(0.9),
+                top_p: Some(0.9),
+                echo: opt.echo,
+                ..Default::default()
+            };
+            let gpt = Bert::new(create_ai(opt.model, 200, 1.1, 0.9)?);
+            print_generation(&gpt, opt.context.unwrap_or_default(), params, opt.usage).await?;
         }
     }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let opt = Opt::from_args();
+    if let Err(error) = run(opt).await {
+        eprintln!("{}", error);
+        std::process::exit(exit_code(&error));
+    }
 }
@@ -0,0 +1,358 @@
+use rocket_okapi::okapi::schemars;
+use rocket_okapi::okapi::schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::ai::AI;
+use crate::batch::BatchMetrics;
+use crate::translate::Translator;
+use crate::worker::ModelThread;
+
+/// What a model serves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelKind {
+    /// Answers completions.
+    Generator,
+    /// Answers `/api/translate`, with its own copy of the model of the same name.
+    Translator,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ModelStatus {
+    pub name: String,
+    pub kind: ModelKind,
+    pub resident: bool,
+    /// Doubled while a second copy of the weights scores tokens for `logprobs`.
+    pub memory_mb: u64,
+    /// Seconds since the model last answered a request, if resident.
+    pub idle_secs: Option<u64>,
+    pub loads: u32,
+    pub evictions: u32,
+    /// Batch sizes achieved since the model was last loaded, if resident.
+    pub batching: Option<BatchMetrics>,
+}
+
+/// A loaded model, a generator or the translation pipeline of a model.
+#[derive(Clone)]
+enum Resident {
+    Generator(Arc<dyn AI>),
+    Translator(Arc<ModelThread<Translator>>),
+}
+
+struct Slot {
+    name: String,
+    memory_mb: u64,
+    kind: ModelKind,
+    model: Option<Resident>,
+    last_used: Instant,
+    last_tick: u64,
+    loads: u32,
+    evictions: u32,
+}
+
+impl Slot {
+    /// Memory the model takes, counting the scorer that evicting it frees too.
+    fn footprint_mb(&self) -> u64 {
+        match &self.model {
+            Some(Resident::Generator(ai)) if ai.scorer_loaded() => 2 * self.memory_mb,
+            _ => self.memory_mb,
+        }
+    }
+}
+
+/// Resident models, evicted least recently used first once the memory budget is exceeded.
+pub(crate) struct ModelCache {
+    budget_mb: Option<u64>,
+    slots: Vec<Slot>,
+    tick: u64,
+}
+
+impl ModelCache {
+    pub fn new(budget_mb: Option<u64>) -> Self {
+        ModelCache {
+            budget_mb,
+            slots: Vec::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn set_budget(&mut self, budget_mb: Option<u64>) {
+        self.budget_mb = budget_mb;
+    }
+
+    /// Adds a servable model without loading it.
+    pub fn add(&mut self, name: &str, memory_mb: u64) {
+        self.add_slot(name, memory_mb, ModelKind::Generator);
+    }
+
+    /// Adds the translation pipeline `name`, which holds a copy of a model of
+    /// `memory_mb`, without loading it.
+    pub fn add_translator(&mut self, name: &str, memory_mb: u64) {
+        self.add_slot(name, memory_mb, ModelKind::Translator);
+    }
+
+    fn add_slot(&mut self, name: &str, memory_mb: u64, kind: ModelKind) {
+        if !self.slots.iter().any(|slot| slot.name == name) {
+            self.slots.push(Slot {
+                name: name.to_string(),
+                memory_mb,
+                kind,
+                model: None,
+                last_used: Instant::now(),
+                last_tick: 0,
+                loads: 0,
+                evictions: 0,
+            });
+        }
+    }
+
+    /// Whether `name` is a servable generator.
+    pub fn contains(&self, name: &str) -> bool {
+        self.slots
+            .iter()
+            .any(|slot| slot.name == name && slot.kind == ModelKind::Generator)
+    }
+
+    /// Servable generators.
+    pub fn names(&self) -> Vec<String> {
+        self.slots
+            .iter()
+            .filter(|slot| slot.kind == ModelKind::Generator)
+            .map(|slot| slot.name.clone())
+            .collect()
+    }
+
+    /// Every slot, generators and translators, to guard their loads.
+    pub fn slot_names(&self) -> Vec<String> {
+        self.slots.iter().map(|slot| slot.name.clone()).collect()
+    }
+
+    pub fn get(&mut self, name: &str) -> Option<Arc<dyn AI>> {
+        match self.get_resident(name)? {
+            Resident::Generator(ai) => Some(ai),
+            Resident::Translator(_) => None,
+        }
+    }
+
+    pub fn get_translator(&mut self, name: &str) -> Option<Arc<ModelThread<Translator>>> {
+        match self.get_resident(name)? {
+            Resident::Translator(translator) => Some(translator),
+            Resident::Generator(_) => None,
+        }
+    }
+
+    fn get_resident(&mut self, name: &str) -> Option<Resident> {
+        self.tick += 1;
+        let slot = self.slots.iter_mut().find(|slot| slot.name == name)?;
+        let model = slot.model.clone()?;
+        slot.last_used = Instant::now();
+        slot.last_tick = self.tick;
+        Some(model)
+    }
+
+    /// Evicts least recently used models until `memory_mb` more fits into the budget.
+    pub fn evict_for(&mut self, memory_mb: u64) -> Vec<String> {
+        self.evict(memory_mb, None)
+    }
+
+    /// Evicts least recently used models other than `keep` until the resident
+    /// ones fit into the budget again, after a model has grown by loading its
+    /// scorer.
+    pub fn evict_over_budget(&mut self, keep: &str) -> Vec<String> {
+        self.evict(0, Some(keep))
+    }
+
+    fn evict(&mut self, memory_mb: u64, keep: Option<&str>) -> Vec<String> {
+        let budget_mb = match self.budget_mb {
+            Some(budget_mb) => budget_mb,
+            None => return Vec::new(),
+        };
+        let mut evicted = Vec::new();
+        while self.resident_mb() + memory_mb > budget_mb {
+            let slot = self
+                .slots
+                .iter_mut()
+                .filter(|slot| slot.model.is_some() && Some(slot.name.as_str()) != keep)
+                .min_by_key(|slot| slot.last_tick);
+            match slot {
+                Some(slot) => {
+                    slot.model = None;
+                    slot.evictions += 1;
+                    evicted.push(slot.name.clone());
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    /// Evicts least recently used models until a model of `memory_mb` fits next
+    /// to the resident ones and the `loading_mb` of loads in progress. Evicts
+    /// nothing and returns `None` if it cannot fit before those loads finish.
+    /// A model larger than the budget on its own loads once nothing else does.
+    pub fn evict_for_load(&mut self, memory_mb: u64, loading_mb: u64) -> Option<Vec<String>> {
+        if let Some(budget_mb) = self.budget_mb {
+            if loading_mb > 0 && loading_mb + memory_mb > budget_mb {
+                return None;
+            }
+        }
+        Some(self.evict_for(memory_mb + loading_mb))
+    }
+
+    pub fn insert(&mut self, name: &str, memory_mb: u64, ai: Arc<dyn AI>) {
+        self.add(name, memory_mb);
+        self.insert_resident(name, Resident::Generator(ai));
+    }
+
+    pub fn insert_translator(
+        &mut self,
+        name: &str,
+        memory_mb: u64,
+        translator: Arc<ModelThread<Translator>>,
+    ) {
+        self.add_translator(name, memory_mb);
+        self.insert_resident(name, Resident::Translator(translator));
+    }
+
+    fn insert_resident(&mut self, name: &str, model: Resident) {
+        self.tick += 1;
+        if let Some(slot) = self.slots.iter_mut().find(|slot| slot.name == name) {
+            slot.model = Some(model);
+            slot.last_used = Instant::now();
+            slot.last_tick = self.tick;
+            slot.loads += 1;
+        }
+    }
+
+    pub fn status(&self) -> Vec<ModelStatus> {
+        self.slots
+            .iter()
+            .map(|slot| ModelStatus {
+                name: slot.name.clone(),
+                kind: slot.kind,
+                resident: slot.model.is_some(),
+                memory_mb: slot.footprint_mb(),
+                idle_secs: slot
+                    .model
+                    .as_ref()
+                    .map(|_| slot.last_used.elapsed().as_secs()),
+                loads: slot.loads,
+                evictions: slot.evictions,
+                batching: match &slot.model {
+                    Some(Resident::Generator(ai)) => ai.batch_metrics(),
+                    _ => None,
+                },
+            })
+            .collect()
+    }
+
+    fn resident_mb(&self) -> u64 {
+        self.slots
+            .iter()
+            .filter(|slot| slot.model.is_some())
+            .map(Slot::footprint_mb)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::tests::Echo;
+    use crate::error::BertError;
+    use crate::generation::Generation;
+    use crate::params::GenerationParams;
+    use async_trait::async_trait;
+
+    /// Echoes with the scorer for `logprobs` loaded.
+    struct Scoring;
+
+    #[async_trait]
+    impl AI for Scoring {
+        async fn response(
+            &self,
+            context: String,
+            params: GenerationParams,
+        ) -> Result<Generation, BertError> {
+            Echo.response(context, params).await
+        }
+
+        fn name(&self) -> String {
+            "scoring".to_string()
+        }
+
+        fn scorer_loaded(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let mut cache = ModelCache::new(Some(1000));
+        cache.insert("a", 400, Arc::new(Echo));
+        cache.insert("b", 400, Arc::new(Echo));
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.evict_for(400), vec!["b".to_string()]);
+        cache.insert("c", 400, Arc::new(Echo));
+        assert!(cache.get("b").is_none());
+        assert_eq!(
+            cache.evict_for(1200),
+            vec!["a".to_string(), "c".to_string()]
+        );
+        let status = cache.status();
+        assert_eq!(status.len(), 3);
+        assert!(status.iter().all(|model| !model.resident));
+        assert_eq!(status[1].evictions, 1);
+    }
+
+    #[test]
+    fn test_evict_for_load() {
+        let mut cache = ModelCache::new(Some(1000));
+        cache.insert("a", 400, Arc::new(Echo));
+        cache.insert("b", 400, Arc::new(Echo));
+        assert_eq!(cache.evict_for_load(300, 0), Some(vec!["a".to_string()]));
+        assert_eq!(cache.evict_for_load(800, 300), None);
+        assert!(cache.get("b").is_some());
+        assert_eq!(cache.evict_for_load(2000, 0), Some(vec!["b".to_string()]));
+    }
+
+    #[test]
+    fn test_count_scorer() {
+        let mut cache = ModelCache::new(Some(1000));
+        cache.insert("a", 400, Arc::new(Scoring));
+        cache.insert("b", 100, Arc::new(Echo));
+        assert_eq!(cache.status()[0].memory_mb, 800);
+        assert_eq!(cache.evict_for(200), vec!["a".to_string()]);
+        assert_eq!(cache.status()[0].memory_mb, 400);
+
+        let mut cache = ModelCache::new(Some(1000));
+        cache.insert("a", 100, Arc::new(Echo));
+        cache.insert("b", 200, Arc::new(Echo));
+        cache.insert("c", 400, Arc::new(Scoring));
+        assert_eq!(cache.evict_over_budget("c"), vec!["a".to_string()]);
+        assert!(cache.evict_over_budget("c").is_empty());
+        assert!(cache.get("b").is_some());
+    }
+
+    #[test]
+    fn test_translator_slots() {
+        let mut cache = ModelCache::new(Some(1000));
+        cache.add("a", 400);
+        cache.add_translator("a-translation", 400);
+        assert_eq!(cache.names(), vec!["a".to_string()]);
+        assert!(!cache.contains("a-translation"));
+        assert_eq!(cache.slot_names().len(), 2);
+        assert_eq!(cache.status()[1].kind, ModelKind::Translator);
+        assert!(cache.get("a-translation").is_none());
+    }
+
+    #[test]
+    fn test_unlimited_budget() {
+        let mut cache = ModelCache::new(None);
+        cache.insert("a", 4000, Arc::new(Echo));
+        assert!(cache.evict_for(u64::MAX / 2).is_empty());
+        assert!(cache.get("a").is_some());
+    }
+}
@@ -0,0 +1,294 @@
+use rust_bert::pipelines::common::{ConfigOption, TokenizerOption};
+use rust_bert::pipelines::zero_shot_classification::{
+    ZeroShotClassificationConfig, ZeroShotClassificationOption,
+};
+use rust_bert::resources::ResourceProvider;
+use rust_bert::RustBertError;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use tch::nn::VarStore;
+use tch::{Device, Kind, Tensor};
+
+use crate::error::BertError;
+use crate::ClassifyResponse;
+
+/// Template used when a request does not bring its own.
+pub const DEFAULT_HYPOTHESIS_TEMPLATE: &str = "This example is about {}.";
+
+/// Longest premise and hypothesis pair, in tokens.
+const MAX_LENGTH: usize = 128;
+
+/// Premise and hypothesis pairs scored per forward pass, which bounds memory
+/// use however many sequences a request brings.
+const PAIRS_PER_PASS: usize = 64;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClassifyOptions {
+    /// Scores every label on its own instead of having the labels share a total of 1.
+    pub multi_label: bool,
+    /// Turns a label into the hypothesis tested against the sequence. `{}`
+    /// stands for the label.
+    pub hypothesis_template: Option<String>,
+}
+
+impl ClassifyOptions {
+    pub fn validate(&self, labels: &[String]) -> Result<(), BertError> {
+        if labels.is_empty() {
+            return Err(BertError::InvalidParameters(
+                "at least one label is required".to_string(),
+            ));
+        }
+        if labels.iter().any(|label| label.is_empty()) {
+            return Err(BertError::InvalidParameters(
+                "labels must not be empty".to_string(),
+            ));
+        }
+        if let Some(template) = &self.hypothesis_template {
+            if !template.contains("{}") {
+                return Err(BertError::InvalidParameters(
+                    "hypothesis_template must contain {} for the label".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Zero-shot classification with BART fine-tuned on MNLI. Every label is
+/// turned into a hypothesis and scored by how strongly the sequence entails it.
+/// rust-bert's pipeline keeps its model private and only reports the best label
+/// of a single-label prediction, so the model is run here to score them all.
+pub struct Classifier {
+    tokenizer: TokenizerOption,
+    model: ZeroShotClassificationOption,
+    var_store: VarStore,
+    bos_token_id: i64,
+    eos_token_id: i64,
+    pad_token_id: i64,
+}
+
+impl Classifier {
+    pub fn new() -> Result<Self, BertError> {
+        let config = ZeroShotClassificationConfig::default();
+        let vocab_path = config.vocab_resource.get_local_path()?;
+        let merges_path = match &config.merges_resource {
+            Some(merges_resource) => Some(merges_resource.get_local_path()?),
+            None => None,
+        };
+        let tokenizer = TokenizerOption::from_file(
+            config.model_type,
+            &vocab_path.to_string_lossy(),
+            merges_path.as_deref().map(Path::to_string_lossy).as_deref(),
+            config.lower_case,
+            config.strip_accents,
+            config.add_prefix_space,
+        )?;
+        let model_config =
+            ConfigOption::from_file(config.model_type, config.config_resource.get_local_path()?);
+        let mut var_store = VarStore::new(config.device);
+        let model =
+            ZeroShotClassificationOption::new(config.model_type, &var_store.root(), &model_config)?;
+        var_store
+            .load(config.model_resource.get_local_path()?)
+            .map_err(RustBertError::from)?;
+        let special = tokenizer.convert_tokens_to_ids(&["<s>", "</s>"]);
+        let pad_token_id = tokenizer.get_pad_id().unwrap_or(1);
+        Ok(Classifier {
+            tokenizer,
+            model,
+            var_store,
+            bos_token_id: special[0],
+            eos_token_id: special[1],
+            pad_token_id,
+        })
+    }
+
+    /// Classifies every sequence, returning labels sorted by descending score.
+    pub fn classify(
+        &self,
+        sequences: &[String],
+        labels: &[String],
+        options: &ClassifyOptions,
+    ) -> Result<Vec<ClassifyResponse>, BertError> {
+        options.validate(labels)?;
+        if sequences.iter().any(|sequence| sequence.trim().is_empty()) {
+            return Err(BertError::InvalidParameters(
+                "sequences must not be empty".to_string(),
+            ));
+        }
+        let per_pass = (PAIRS_PER_PASS / labels.len()).max(1);
+        let mut responses = Vec::with_capacity(sequences.len());
+        for chunk in sequences.chunks(per_pass) {
+            responses.extend(self.classify_chunk(chunk, labels, options)?);
+        }
+        Ok(responses)
+    }
+
+    fn classify_chunk(
+        &self,
+        sequences: &[String],
+        labels: &[String],
+        options: &ClassifyOptions,
+    ) -> Result<Vec<ClassifyResponse>, BertError> {
+        let template = options
+            .hypothesis_template
+            .as_deref()
+            .unwrap_or(DEFAULT_HYPOTHESIS_TEMPLATE);
+        let hypotheses: Vec<String> = labels
+            .iter()
+            .map(|label| template.replace("{}", label))
+            .collect();
+        let logits = panic::catch_unwind(AssertUnwindSafe(|| self.logits(sequences, &hypotheses)))
+            .map_err(BertError::from_panic)?;
+        Ok(sequences
+            .iter()
+            .enumerate()
+            .map(|(index, sequence)| {
+                // Logits are contradiction, neutral and entailment.
+                let logits = logits.get(index as i64);
+                let entailment = Vec::<f64>::from(&logits.select(1, 2));
+                let scores = if options.multi_label {
+                    let contradiction = Vec::<f64>::from(&logits.select(1, 0));
+                    entailment_probabilities(&contradiction, &entailment)
+                } else {
+                    softmax(&entailment)
+                };
+                let mut ranked: Vec<(String, f64)> = labels.iter().cloned().zip(scores).collect();
+                ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+                let (labels, scores) = ranked
+                    .into_iter()
+                    .map(|(label, score)| (label, score as f32))
+                    .unzip();
+                ClassifyResponse {
+                    sequence: sequence.clone(),
+                    labels,
+                    scores,
+                }
+            })
+            .collect())
+    }
+
+    /// NLI logits of every sequence paired with every hypothesis, as a tensor
+    /// on the CPU of shape (*sequences*, *hypotheses*, 3).
+    fn logits(&self, sequences: &[String], hypotheses: &[String]) -> Tensor {
+        let hypotheses: Vec<Vec<i64>> = hypotheses
+            .iter()
+            .map(|hypothesis| self.token_ids(hypothesis))
+            .collect();
+        let pairs: Vec<Vec<i64>> = sequences
+            .iter()
+            .flat_map(|sequence| {
+                let premise = self.token_ids(sequence);
+                hypotheses
+                    .iter()
+                    .map(move |hypothesis| self.pair(premise.clone(), hypothesis.clone()))
+            })
+            .collect();
+        let length = pairs.iter().map(Vec::len).max().unwrap_or(0);
+        let rows: Vec<Tensor> = pairs
+            .into_iter()
+            .map(|mut pair| {
+                pair.resize(length, self.pad_token_id);
+                Tensor::of_slice(&pair)
+            })
+            .collect();
+        let input = Tensor::stack(&rows, 0).to(self.var_store.device());
+        let mask = input.ne(self.pad_token_id).to_kind(Kind::Bool);
+        let logits = tch::no_grad(|| {
+            self.model
+                .forward_t(Some(&input), Some(&mask), None, None, None, false)
+        });
+        logits
+            .view([sequences.len() as i64, hypotheses.len() as i64, -1])
+            .to_kind(Kind::Double)
+            .to_device(Device::Cpu)
+    }
+
+    fn token_ids(&self, text: &str) -> Vec<i64> {
+        self.tokenizer
+            .convert_tokens_to_ids(&self.tokenizer.tokenize(text))
+    }
+
+    /// BART's encoding of a premise and hypothesis pair, shortened to
+    /// `MAX_LENGTH` by dropping tokens from the end of the longer one.
+    fn pair(&self, mut premise: Vec<i64>, mut hypothesis: Vec<i64>) -> Vec<i64> {
+        while premise.len() + hypothesis.len() + 4 > MAX_LENGTH {
+            if premise.len() >= hypothesis.len() {
+                premise.pop();
+            } else {
+                hypothesis.pop();
+            }
+        }
+        let mut pair = vec![self.bos_token_id];
+        pair.extend(premise);
+        pair.extend([self.eos_token_id, self.eos_token_id]);
+        pair.extend(hypothesis);
+        pair.push(self.eos_token_id);
+        pair
+    }
+}
+
+/// Single-label scores: a softmax of the entailment logits across labels.
+fn softmax(logits: &[f64]) -> Vec<f64> {
+    let max = logits.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let weights: Vec<f64> = logits.iter().map(|logit| (logit - max).exp()).collect();
+    let total: f64 = weights.iter().sum();
+    weights.iter().map(|weight| weight / total).collect()
+}
+
+/// Multi-label scores: the probability of entailment over contradiction of
+/// every label on its own.
+fn entailment_probabilities(contradiction: &[f64], entailment: &[f64]) -> Vec<f64> {
+    contradiction
+        .iter()
+        .zip(entailment)
+        .map(|(contradiction, entailment)| 1.0 / (1.0 + (contradiction - entailment).exp()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scores() {
+        let scores = softmax(&[2.0, 1.0, 0.0]);
+        assert!((scores.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!((scores[1] - 1.0_f64.exp() / (1.0 + 1.0_f64.exp() + 2.0_f64.exp())).abs() < 1e-9);
+        let probabilities = entailment_probabilities(&[0.0, 3.0], &[0.0, 1.0]);
+        assert!((probabilities[0] - 0.5).abs() < 1e-9);
+        assert!(probabilities[1] < 0.5);
+    }
+
+    #[test]
+    fn test_classify() {
+        let classifier = Classifier::new().unwrap();
+        let sequences = vec!["Who are you voting for in 2020?".to_string()];
+        let labels: Vec<String> = ["politics", "economics", "public health"]
+            .iter()
+            .map(|label| label.to_string())
+            .collect();
+        let response = classifier
+            .classify(&sequences, &labels, &ClassifyOptions::default())
+            .unwrap()
+            .remove(0);
+        assert_eq!(response.labels[0], "politics");
+        assert!((response.scores.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+
+        let options = ClassifyOptions {
+            multi_label: true,
+            hypothesis_template: Some("This text is about {}.".to_string()),
+        };
+        let response = classifier
+            .classify(&sequences, &labels, &options)
+            .unwrap()
+            .remove(0);
+        assert_eq!(response.labels.len(), 3);
+        assert!(response.scores.windows(2).all(|pair| pair[0] >= pair[1]));
+
+        let sequences = vec![sequences[0].clone(), " ".to_string()];
+        assert!(classifier
+            .classify(&sequences, &labels, &ClassifyOptions::default())
+            .is_err());
+    }
+}
@@ -2,6 +2,12 @@ use serde::Deserialize;
 #[derive(Debug, Default, Deserialize)]
 pub struct Config {
     pub model: String,
+    #[serde(default)]
+    pub models: Vec<String>,
+    pub memory_budget_mb: Option<u64>,
+    pub max_batch_size: Option<usize>,
+    pub max_batch_wait_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
     pub token_max_length: u16,
     pub temperature: f32,
     pub top_p: f32,
@@ -0,0 +1,147 @@
+use rust_bert::pipelines::common::TokenizerOption;
+
+/// Token sequences of `words`, both at the start of the text and after a space.
+pub(crate) fn bad_word_ids(tokenizer: &TokenizerOption, words: &[String]) -> Vec<Vec<i64>> {
+    let mut ids: Vec<Vec<i64>> = Vec::new();
+    for word in words {
+        for variant in [word.clone(), format!(" {}", word)] {
+            let tokens = tokenizer.convert_tokens_to_ids(&tokenizer.tokenize(&variant));
+            if !tokens.is_empty() && !ids.contains(&tokens) {
+                ids.push(tokens);
+            }
+        }
+    }
+    ids
+}
+
+/// What a sequence must do next to contain every forced phrase.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Forced {
+    /// Every phrase has been generated.
+    Done,
+    /// Phrases are missing but still fit, the sequence may not end yet.
+    Pending,
+    /// Phrases only just fit, this token must come next.
+    Next(i64),
+}
+
+/// Phrases every completion must contain. Missing phrases are written out
+/// just in time: once the tokens left before `max_length` only suffice for
+/// the missing phrases, decoding is restricted to their tokens.
+pub(crate) struct ForcedWords {
+    phrases: Vec<(String, Vec<i64>)>,
+    max_length: i64,
+}
+
+impl ForcedWords {
+    pub fn new(phrases: Vec<(String, Vec<i64>)>, max_length: i64) -> Self {
+        ForcedWords {
+            phrases,
+            max_length,
+        }
+    }
+
+    /// Phrases are forced as they would appear after a space.
+    pub fn from_words(tokenizer: &TokenizerOption, words: &[String], max_length: i64) -> Self {
+        let phrases = words
+            .iter()
+            .map(|word| {
+                let tokens = tokenizer.tokenize(&format!(" {}", word));
+                (word.clone(), tokenizer.convert_tokens_to_ids(&tokens))
+            })
+            .collect();
+        ForcedWords::new(phrases, max_length)
+    }
+
+    /// Tokens the phrases take together.
+    pub fn tokens(&self) -> usize {
+        self.phrases.iter().map(|(_, tokens)| tokens.len()).sum()
+    }
+
+    /// `generated` holds the tokens generated so far, `text` their decoding and
+    /// `length` the length of the whole sequence.
+    pub fn next(&self, generated: &[i64], text: &str, length: i64) -> Forced {
+        let pending: Vec<&[i64]> = self
+            .phrases
+            .iter()
+            .filter(|(phrase, _)| !contains_phrase(text, phrase))
+            .map(|(_, tokens)| tokens.as_slice())
+            .collect();
+        if pending.is_empty() {
+            return Forced::Done;
+        }
+        // The longest start of a phrase that the sequence currently ends with.
+        let progress = pending
+            .iter()
+            .enumerate()
+            .filter_map(|(index, tokens)| {
+                (1..tokens.len())
+                    .rev()
+                    .find(|written| generated.ends_with(&tokens[..*written]))
+                    .map(|written| (index, written))
+            })
+            .max_by_key(|(_, written)| *written);
+        let required = pending.iter().map(|tokens| tokens.len()).sum::<usize>()
+            - progress.map_or(0, |(_, written)| written);
+        if self.max_length - length > required as i64 {
+            return Forced::Pending;
+        }
+        match progress {
+            Some((index, written)) => Forced::Next(pending[index][written]),
+            None => Forced::Next(pending[0][0]),
+        }
+    }
+}
+
+/// Whether `text` contains `phrase` other than inside a longer word, so that
+/// "electricity" does not contain "electric".
+fn contains_phrase(text: &str, phrase: &str) -> bool {
+    let joined = |a: Option<char>, b: Option<char>| {
+        a.map_or(false, char::is_alphanumeric) && b.map_or(false, char::is_alphanumeric)
+    };
+    text.match_indices(phrase).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + phrase.len()..].chars().next();
+        !joined(before, phrase.chars().next()) && !joined(phrase.chars().next_back(), after)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forced_words() {
+        let forced = ForcedWords::new(
+            vec![
+                ("red fox".to_string(), vec![10, 11]),
+                ("dog".to_string(), vec![20]),
+            ],
+            10,
+        );
+        assert_eq!(forced.next(&[], "", 4), Forced::Pending);
+        assert_eq!(forced.next(&[1, 2, 3], "a b c", 7), Forced::Next(10));
+        assert_eq!(
+            forced.next(&[1, 2, 3, 10], "a b c red", 8),
+            Forced::Next(11)
+        );
+        assert_eq!(
+            forced.next(&[1, 2, 3, 10, 11], "a b c red fox", 9),
+            Forced::Next(20)
+        );
+        assert_eq!(forced.next(&[1, 20], "a dog", 4), Forced::Pending);
+        assert_eq!(forced.next(&[20, 10, 11], "dog red fox", 5), Forced::Done);
+        assert_eq!(forced.tokens(), 3);
+    }
+
+    #[test]
+    fn test_contains_phrase() {
+        assert!(contains_phrase("it runs on electric power", "electric"));
+        assert!(contains_phrase("electric.", "electric"));
+        assert!(!contains_phrase("electricity", "electric"));
+        assert!(!contains_phrase("hydroelectric", "electric"));
+        assert!(contains_phrase("hydroelectric electric", "electric"));
+        assert!(contains_phrase("a red fox!", "red fox"));
+        assert!(contains_phrase("costs 5$", "$"));
+    }
+}
@@ -0,0 +1,70 @@
+use rust_bert::RustBertError;
+use std::any::Any;
+use std::error;
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub enum BertError {
+    ModelLoad(String),
+    InvalidParameters(String),
+    TokenizationOverflow {
+        tokens: usize,
+        max_tokens: usize,
+    },
+    Inference(String),
+    UnknownModel {
+        name: String,
+        suggestions: Vec<String>,
+    },
+    Cancelled,
+}
+
+/// Panic payload that abandons a generation nobody waits for anymore.
+pub(crate) struct Cancelled;
+
+impl BertError {
+    /// Converts the payload of a panic inside rust-bert or tch into an inference error.
+    pub(crate) fn from_panic(payload: Box<dyn Any + Send>) -> Self {
+        if payload.is::<Cancelled>() {
+            return BertError::Cancelled;
+        }
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "model panicked".to_string(),
+            },
+        };
+        BertError::Inference(message)
+    }
+}
+
+impl fmt::Display for BertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BertError::ModelLoad(message) => write!(f, "failed to load model: {}", message),
+            BertError::InvalidParameters(message) => write!(f, "invalid parameters: {}", message),
+            BertError::TokenizationOverflow { tokens, max_tokens } => write!(
+                f,
+                "prompt has {} tokens but the model accepts at most {}",
+                tokens, max_tokens
+            ),
+            BertError::Inference(message) => write!(f, "inference failed: {}", message),
+            BertError::UnknownModel { name, suggestions } => write!(
+                f,
+                "unknown model: {} (did you mean: {}?)",
+                name,
+                suggestions.join(", ")
+            ),
+            BertError::Cancelled => write!(f, "request cancelled"),
+        }
+    }
+}
+
+impl error::Error for BertError {}
+
+impl From<RustBertError> for BertError {
+    fn from(error: RustBertError) -> Self {
+        BertError::ModelLoad(error.to_string())
+    }
+}
@@ -0,0 +1,785 @@
+use rocket::serde::json::serde_json::{self, Value};
+use rocket_okapi::okapi::schemars;
+use rocket_okapi::okapi::schemars::JsonSchema;
+use rust_bert::pipelines::common::TokenizerOption;
+use rust_bert::pipelines::generation_utils::{
+    GenerateConfig, GenerateOptions, GeneratedIndicesOutput,
+};
+use rust_bert::resources::RemoteResource;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{PoisonError, RwLock};
+use std::time::Instant;
+use tch::Tensor;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::bias::{self, TokenBias};
+use crate::constraints::{self, Forced, ForcedWords};
+use crate::error::{BertError, Cancelled};
+use crate::logprobs::{token_logprobs, Logprob};
+use crate::params::{GenerationParams, Truncation};
+
+/// tch samples from a single global random number generator.
+static RNG: RwLock<()> = RwLock::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// `max_length` was reached, a continuation may follow.
+    Length,
+    StopSequence,
+    /// The model ended the text itself.
+    Eos,
+    Timeout,
+}
+
+/// One candidate completion.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Choice {
+    pub text: String,
+    /// Log-likelihood of the sequence, where the backend reports one.
+    pub score: Option<f64>,
+    pub finish_reason: FinishReason,
+    /// Generated tokens with their log-probabilities, when `logprobs` is set.
+    pub logprobs: Option<Vec<Logprob>>,
+}
+
+/// Token counts of a request, measured with the model's tokenizer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    /// Tokens of all returned choices together.
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+    /// Prompt tokens dropped to fit the model, see `truncation`.
+    pub dropped_tokens: usize,
+}
+
+impl Usage {
+    pub fn new(prompt_tokens: usize, completion_tokens: usize) -> Self {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            dropped_tokens: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Generation {
+    pub choices: Vec<Choice>,
+    pub usage: Usage,
+    /// The biases applied, by token.
+    pub logit_bias: Vec<TokenBias>,
+}
+
+/// Text deltas of a generation in progress, followed by the finished generation.
+pub struct GenerationStream {
+    model: String,
+    revision: Option<String>,
+    seed: Option<u64>,
+    deltas: mpsc::UnboundedReceiver<String>,
+    result: oneshot::Receiver<Result<Generation, BertError>>,
+}
+
+impl GenerationStream {
+    pub fn new(
+        model: String,
+        revision: Option<String>,
+        deltas: mpsc::UnboundedReceiver<String>,
+        result: oneshot::Receiver<Result<Generation, BertError>>,
+    ) -> Self {
+        GenerationStream {
+            model,
+            revision,
+            seed: None,
+            deltas,
+            result,
+        }
+    }
+
+    pub(crate) fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn revision(&self) -> Option<&str> {
+        self.revision.as_deref()
+    }
+
+    /// The seed the request brought, if any.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// Waits for the next piece of text, or `None` once generation has finished.
+    pub async fn next_delta(&mut self) -> Option<String> {
+        self.deltas.recv().await
+    }
+
+    /// Waits for the finished generation.
+    pub async fn finish(self) -> Result<Generation, BertError> {
+        self.result
+            .await
+            .map_err(|_| BertError::Inference("generation stopped".to_string()))?
+    }
+}
+
+/// Checked before every decoding step.
+pub struct Interrupt<'a> {
+    /// Once passed, EOS is forced and the text generated so far is returned.
+    pub deadline: Option<Instant>,
+    /// Once this returns `true`, generation is abandoned.
+    pub cancelled: &'a dyn Fn() -> bool,
+}
+
+/// Settings of a backend's `GenerateConfig`, which rust-bert falls back to for
+/// options a request leaves out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Defaults {
+    pub max_length: i64,
+    pub min_length: i64,
+}
+
+impl From<&GenerateConfig> for Defaults {
+    fn from(config: &GenerateConfig) -> Self {
+        Defaults {
+            max_length: config.max_length,
+            min_length: config.min_length,
+        }
+    }
+}
+
+/// Token level access to a rust-bert `LanguageGenerator`.
+pub trait Generator {
+    fn generate_indices(
+        &self,
+        prompts: &[String],
+        options: GenerateOptions,
+    ) -> Vec<GeneratedIndicesOutput>;
+    fn tokenizer(&self) -> &TokenizerOption;
+    fn vocab_size(&self) -> i64;
+    fn eos_token_id(&self) -> i64;
+    fn max_positions(&self) -> i64;
+    fn defaults(&self) -> &Defaults;
+    /// Identifies the weights, see `revision`.
+    fn revision(&self) -> &str;
+
+    /// Encoder-decoder models encode the prompt on its own and decode from
+    /// start tokens, causal models continue the prompt within `max_length`.
+    fn is_encoder_decoder(&self) -> bool {
+        false
+    }
+
+    /// Whether `logits` is available.
+    fn scores_tokens(&self) -> bool {
+        false
+    }
+
+    /// Next-token logits at every position of `tokens`, see `Scorer::logits`.
+    fn logits(&self, _tokens: &[i64]) -> Result<Tensor, BertError> {
+        Err(unsupported("scoring tokens"))
+    }
+
+    /// Whether `logits` has loaded the second copy of the weights it scores with.
+    fn scorer_loaded(&self) -> bool {
+        false
+    }
+}
+
+fn unsupported(feature: &str) -> BertError {
+    BertError::InvalidParameters(format!("this model does not support {}", feature))
+}
+
+/// `<repository>@<etag>` of weights downloaded from the Hugging Face hub to
+/// `local_path`, or `<url>@<etag>` for weights hosted elsewhere. The ref in the
+/// URL names a branch that can move; the ETag of a file stored with Git LFS is
+/// the SHA-256 of its content. cached-path keeps it in `<local_path>.meta`,
+/// and names downloads after it, so the file name stands in when that is gone.
+pub(crate) fn revision(resource: &RemoteResource, local_path: &Path) -> String {
+    let mut meta_path = local_path.as_os_str().to_owned();
+    meta_path.push(".meta");
+    let etag = fs::read(meta_path)
+        .ok()
+        .and_then(|meta| serde_json::from_slice::<Value>(&meta).ok())
+        .and_then(|meta| meta["etag"].as_str().map(str::to_string));
+    let version = match etag {
+        Some(etag) => etag.trim_start_matches("W/").trim_matches('"').to_string(),
+        None => local_path
+            .file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().into_owned()),
+    };
+    let source = resource
+        .url
+        .strip_prefix("https://huggingface.co/")
+        .and_then(|path| path.split_once("/resolve/"))
+        .map_or(resource.url.as_str(), |(repository, _)| repository);
+    format!("{}@{}", source, version)
+}
+
+/// Generates a continuation for every prompt in `contexts` as one batch.
+/// Prompts that do not fit the model fail on their own.
+pub fn generate(
+    generator: &dyn Generator,
+    contexts: &[String],
+    params: &GenerationParams,
+    interrupt: &Interrupt,
+) -> Vec<Result<Generation, BertError>> {
+    let checked: Vec<Result<(String, usize), BertError>> = contexts
+        .iter()
+        .map(|context| {
+            let (prompt, dropped_tokens) = fit_prompt(generator, context, params)?;
+            check_force_words(generator, &prompt, params)?;
+            Ok((prompt, dropped_tokens))
+        })
+        .collect();
+    let prompts: Vec<String> = checked
+        .iter()
+        .filter_map(|checked| checked.as_ref().ok())
+        .map(|(prompt, _)| prompt.clone())
+        .collect();
+
+    let generations: Vec<Result<Generation, BertError>> =
+        match generate_batch(generator, &prompts, params, interrupt, None) {
+            Ok(generations) => generations.into_iter().map(Ok).collect(),
+            Err(err) => vec![Err(err); prompts.len()],
+        };
+    let mut generations = generations.into_iter();
+    checked
+        .into_iter()
+        .map(|checked| {
+            let (_, dropped_tokens) = checked?;
+            let mut generation = generations.next().unwrap_or_else(|| {
+                Err(BertError::Inference("model returned no output".to_string()))
+            })?;
+            generation.usage.dropped_tokens = dropped_tokens;
+            Ok(generation)
+        })
+        .collect()
+}
+
+/// Generates a continuation for `context`, passing text to `on_delta` as it is
+/// decoded. Text that may still turn out to be part of a stop sequence is held
+/// back until it is certain.
+pub fn generate_streaming(
+    generator: &dyn Generator,
+    context: &str,
+    params: &GenerationParams,
+    interrupt: &Interrupt,
+    on_delta: &dyn Fn(String),
+) -> Result<Generation, BertError> {
+    let (prompt, dropped_tokens) = fit_prompt(generator, context, params)?;
+    check_force_words(generator, &prompt, params)?;
+    let context = prompt.as_str();
+    let params = GenerationParams {
+        num_beams: Some(1),
+        ..params.clone()
+    };
+    if params.echo {
+        on_delta(context.to_string());
+    }
+    let hold_back = params.stop.iter().map(String::len).max().unwrap_or(1) - 1;
+    let emitted = RefCell::new(String::new());
+    let emit = |text: &str| {
+        let mut emitted = emitted.borrow_mut();
+        if text.len() > emitted.len() && text.starts_with(emitted.as_str()) {
+            on_delta(text[emitted.len()..].to_string());
+            *emitted = text.to_string();
+        }
+    };
+    let on_text = |batch_id: i64, text: &str| {
+        if batch_id != 0 {
+            return;
+        }
+        // A trailing replacement character is a partially decoded character.
+        let text = text.trim_end_matches('\u{FFFD}');
+        let mut end =
+            find_stop(text, &params.stop).unwrap_or_else(|| text.len().saturating_sub(hold_back));
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        emit(&text[..end]);
+    };
+
+    let prompts = [context.to_string()];
+    let mut generation = generate_batch(generator, &prompts, &params, interrupt, Some(&on_text))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| BertError::Inference("model returned no output".to_string()))?;
+    generation.usage.dropped_tokens = dropped_tokens;
+    if let Some(choice) = generation.choices.first() {
+        let continuation = if params.echo {
+            choice.text.strip_prefix(context).unwrap_or(&choice.text)
+        } else {
+            &choice.text
+        };
+        emit(continuation);
+    }
+    Ok(generation)
+}
+
+/// Fails like generating a continuation for `context` would before decoding,
+/// without decoding.
+pub fn check(
+    generator: &dyn Generator,
+    context: &str,
+    params: &GenerationParams,
+) -> Result<(), BertError> {
+    check_support(generator, params)?;
+    bias::resolve(
+        generator.tokenizer(),
+        generator.vocab_size(),
+        &params.logit_bias,
+    )?;
+    let (prompt, _) = fit_prompt(generator, context, params)?;
+    check_force_words(generator, &prompt, params)
+}
+
+fn check_support(generator: &dyn Generator, params: &GenerationParams) -> Result<(), BertError> {
+    if params.logprobs.is_some() && !generator.scores_tokens() {
+        return Err(unsupported("logprobs"));
+    }
+    Ok(())
+}
+
+/// Fits `context` into the prompt tokens the model accepts, trimming it as
+/// `truncation` asks. Returns the prompt and the number of tokens dropped from it.
+fn fit_prompt(
+    generator: &dyn Generator,
+    context: &str,
+    params: &GenerationParams,
+) -> Result<(String, usize), BertError> {
+    let tokenizer = generator.tokenizer();
+    let max_tokens = max_prompt_tokens(generator, params);
+    let tokens = tokenizer.tokenize(context).len();
+    if tokens <= max_tokens {
+        return Ok((context.to_string(), 0));
+    }
+    if params.truncation == Truncation::Error || max_tokens == 0 {
+        return Err(BertError::TokenizationOverflow { tokens, max_tokens });
+    }
+    let mut prompt = context.to_string();
+    let mut remaining = tokens;
+    // Text cut at a token boundary can tokenize differently, so trim until it fits.
+    while remaining > max_tokens {
+        let trimmed = truncate(tokenizer, &prompt, max_tokens, params.truncation);
+        if trimmed.len() >= prompt.len() {
+            return Err(BertError::TokenizationOverflow { tokens, max_tokens });
+        }
+        remaining = tokenizer.tokenize(&trimmed).len();
+        prompt = trimmed;
+    }
+    Ok((prompt, tokens - remaining))
+}
+
+/// The `max_length` a batch whose longest prompt has `longest_prompt` tokens
+/// is decoded to. Causal prompts are cut to `max_length` before decoding, so
+/// it is derived from the longest prompt when `max_new_tokens` is given.
+fn max_length(generator: &dyn Generator, params: &GenerationParams, longest_prompt: usize) -> i64 {
+    let max_length = match params.max_new_tokens {
+        Some(max_new_tokens) if !generator.is_encoder_decoder() => {
+            longest_prompt as i64 + i64::from(max_new_tokens)
+        }
+        _ => params
+            .max_length
+            .map_or(generator.defaults().max_length, i64::from),
+    };
+    // Positions past the model's embeddings fail inside tch.
+    max_length.min(generator.max_positions())
+}
+
+/// Fails if `force_words` cannot be written out between `prompt` and `max_length`.
+fn check_force_words(
+    generator: &dyn Generator,
+    prompt: &str,
+    params: &GenerationParams,
+) -> Result<(), BertError> {
+    if params.force_words.is_empty() {
+        return Ok(());
+    }
+    let prompt_length = prompt_tokens(generator, prompt).len();
+    let max_length = max_length(generator, params, prompt_length);
+    // Encoder-decoder models decode after a single start token.
+    let start = if generator.is_encoder_decoder() {
+        1
+    } else {
+        prompt_length as i64
+    };
+    let left = (max_length - start).max(0) as usize;
+    let tokens =
+        ForcedWords::from_words(generator.tokenizer(), &params.force_words, max_length).tokens();
+    if tokens > left {
+        return Err(BertError::InvalidParameters(format!(
+            "force_words take {} tokens, but only {} are left to generate",
+            tokens, left
+        )));
+    }
+    Ok(())
+}
+
+/// Encoder-decoder models take prompts up to their positions, less the special
+/// tokens the tokenizer adds. rust-bert cuts causal prompts to `max_length`,
+/// so they must leave room for `max_new_tokens`, or at least one token, within
+/// the `max_length` that applies.
+fn max_prompt_tokens(generator: &dyn Generator, params: &GenerationParams) -> usize {
+    let max_positions = generator.max_positions() as usize;
+    if generator.is_encoder_decoder() {
+        return max_positions.saturating_sub(2);
+    }
+    match params.max_new_tokens {
+        Some(max_new_tokens) => max_positions.saturating_sub(usize::from(max_new_tokens)),
+        None => {
+            let max_length = params
+                .max_length
+                .map_or(generator.defaults().max_length as usize, usize::from);
+            max_length.min(max_positions).saturating_sub(1)
+        }
+    }
+}
+
+/// Cuts `text` at the token boundary that leaves its last (`Left`) or first
+/// (`Right`) `max_tokens` tokens.
+fn truncate(
+    tokenizer: &TokenizerOption,
+    text: &str,
+    max_tokens: usize,
+    truncation: Truncation,
+) -> String {
+    let offsets = tokenizer.tokenize_with_offsets(text).offsets;
+    if offsets.len() <= max_tokens {
+        return text.to_string();
+    }
+    // Offsets count characters.
+    let byte_index = |chars: u32| {
+        text.char_indices()
+            .nth(chars as usize)
+            .map_or(text.len(), |(index, _)| index)
+    };
+    if truncation == Truncation::Right {
+        let end = offsets[..max_tokens]
+            .iter()
+            .flatten()
+            .last()
+            .map_or(0, |offset| offset.end);
+        text[..byte_index(end)].to_string()
+    } else {
+        let begin = offsets[offsets.len() - max_tokens..]
+            .iter()
+            .flatten()
+            .next()
+            .map_or(text.len() as u32, |offset| offset.begin);
+        text[byte_index(begin)..].to_string()
+    }
+}
+
+/// `on_text` receives the text generated so far for each batch entry after every step.
+fn generate_batch(
+    generator: &dyn Generator,
+    prompts: &[String],
+    params: &GenerationParams,
+    interrupt: &Interrupt,
+    on_text: Option<&dyn Fn(i64, &str)>,
+) -> Result<Vec<Generation>, BertError> {
+    if prompts.is_empty() {
+        return Ok(Vec::new());
+    }
+    check_support(generator, params)?;
+    let tokenizer = generator.tokenizer();
+    let biases = bias::resolve(tokenizer, generator.vocab_size(), &params.logit_bias)?;
+    let bad_word_ids = constraints::bad_word_ids(tokenizer, &params.bad_words);
+    let mut options = params.generate_options();
+    let longest_prompt = prompts
+        .iter()
+        .map(|prompt| prompt_tokens(generator, prompt).len())
+        .max()
+        .unwrap_or(0);
+    options.max_length = Some(max_length(generator, params, longest_prompt));
+    if !bad_word_ids.is_empty() {
+        options.bad_word_ids = Some(&bad_word_ids);
+    }
+
+    // Banned tokens are left out of the tokens allowed at every step, rather
+    // than passed as `bad_word_ids`, so that EOS can still be forced if banned.
+    let allowed_tokens: Vec<i64> = (0..generator.vocab_size())
+        .filter(|token| biases.iter().all(|bias| bias.token_id != *token))
+        .collect();
+    let eos_token_id = generator.eos_token_id();
+    let eos_token = vec![eos_token_id];
+    let forced = match options.max_length {
+        Some(max_length) if !params.force_words.is_empty() => Some(ForcedWords::from_words(
+            tokenizer,
+            &params.force_words,
+            max_length,
+        )),
+        _ => None,
+    };
+    let without_eos: Vec<i64> = match forced {
+        Some(_) => allowed_tokens
+            .iter()
+            .copied()
+            .filter(|token| *token != eos_token_id)
+            .collect(),
+        None => Vec::new(),
+    };
+    // Forcing EOS below the minimum length would mask every token, so stop
+    // sequences and timeouts wait for the `min_length` rust-bert decodes with.
+    let min_length = params
+        .min_length
+        .map_or(generator.defaults().min_length, i64::from);
+    let decode = !params.stop.is_empty() || on_text.is_some() || forced.is_some();
+    // Prompts are padded to the same length, and the first call sees the
+    // prompt (or decoder start) tokens only.
+    let prompt_length = Cell::new(None);
+    // Every row of a step has the same length. The deadline is checked once
+    // per step so that all rows stop together.
+    let step_length = Cell::new(None);
+    let expired = Cell::new(false);
+    let timed_out_at = Cell::new(None);
+    let step_fn = |batch_id: i64, tokens: &Tensor| -> Vec<i64> {
+        if (interrupt.cancelled)() {
+            panic::resume_unwind(Box::new(Cancelled));
+        }
+        let length = tokens.size()[0];
+        let start = prompt_length.get().unwrap_or(length);
+        prompt_length.set(Some(start));
+        if step_length.get() != Some(length) {
+            step_length.set(Some(length));
+            expired.set(
+                interrupt
+                    .deadline
+                    .map_or(false, |deadline| Instant::now() >= deadline),
+            );
+        }
+        let (generated, text) = if length > start && decode {
+            let generated: Vec<i64> = tokens.iter::<i64>().unwrap().skip(start as usize).collect();
+            let text = tokenizer.decode(&generated, true, true);
+            if let Some(on_text) = on_text {
+                on_text(batch_id, &text);
+            }
+            if length >= min_length && find_stop(&text, &params.stop).is_some() {
+                return eos_token.clone();
+            }
+            (generated, text)
+        } else {
+            (Vec::new(), String::new())
+        };
+        if length >= min_length && expired.get() {
+            timed_out_at.set(timed_out_at.get().or(Some(length as usize)));
+            return eos_token.clone();
+        }
+        if let Some(forced) = &forced {
+            match forced.next(&generated, &text, length) {
+                Forced::Done => {}
+                Forced::Pending => return without_eos.clone(),
+                Forced::Next(token) => return vec![token],
+            }
+        }
+        allowed_tokens.clone()
+    };
+    options.prefix_allowed_tokens_fn = Some(&step_fn);
+
+    let output = panic::catch_unwind(AssertUnwindSafe(|| {
+        with_seed(params.seed, || generator.generate_indices(prompts, options))
+    }))
+    .map_err(BertError::from_panic)?;
+    // Sequences are grouped by prompt. Backends may be configured to return
+    // more sequences than were asked for.
+    let sequences = output.len() / prompts.len();
+    if sequences == 0 {
+        return Err(BertError::Inference("model returned no output".to_string()));
+    }
+    // Output rows repeat the (padded) prompt, or the decoder start tokens of
+    // encoder-decoder models, ahead of the generated tokens. Without a single
+    // decoding step, they hold nothing else.
+    let start = match prompt_length.get() {
+        Some(length) => length as usize,
+        None => output[0].indices.len(),
+    };
+    if !generator.is_encoder_decoder() && start < longest_prompt {
+        return Err(BertError::Inference(format!(
+            "the model received {} of {} prompt tokens",
+            start, longest_prompt
+        )));
+    }
+    prompts
+        .iter()
+        .zip(output.chunks(sequences))
+        .map(|(context, outputs)| {
+            let (mut choices, tokens): (Vec<Choice>, Vec<usize>) = outputs
+                .iter()
+                .take(params.choices())
+                .map(|output| {
+                    let generated = output.indices.get(start..).unwrap_or(&[]);
+                    let mut text = tokenizer.decode(generated, true, true);
+                    // Rows that were still running when EOS was forced timed out.
+                    let timed_out = timed_out_at.get().map_or(false, |at| {
+                        at > 0
+                            && output.indices.get(at) == Some(&eos_token_id)
+                            && output.indices.get(at - 1) != Some(&eos_token_id)
+                    });
+                    let finish_reason = match find_stop(&text, &params.stop) {
+                        Some(index) => {
+                            text.truncate(index);
+                            FinishReason::StopSequence
+                        }
+                        None if timed_out => FinishReason::Timeout,
+                        None if generated.contains(&eos_token_id) => FinishReason::Eos,
+                        None => FinishReason::Length,
+                    };
+                    let generated = text_tokens(generator, generated, &text);
+                    let logprobs = match params.logprobs {
+                        Some(top) => Some(choice_logprobs(generator, context, generated, top)?),
+                        None => None,
+                    };
+                    let choice = Choice {
+                        text,
+                        score: output.score,
+                        finish_reason,
+                        logprobs,
+                    };
+                    Ok((choice, generated.len()))
+                })
+                .collect::<Result<Vec<(Choice, usize)>, BertError>>()?
+                .into_iter()
+                .unzip();
+            let usage = Usage::new(tokenizer.tokenize(context).len(), tokens.iter().sum());
+            if params.echo {
+                for choice in &mut choices {
+                    choice.text.insert_str(0, context);
+                }
+            }
+            Ok(Generation {
+                choices,
+                usage,
+                logit_bias: biases.clone(),
+            })
+        })
+        .collect()
+}
+
+/// The tokens of `generated` behind `text`, the choice's text before `echo`.
+fn text_tokens<'a>(generator: &dyn Generator, generated: &'a [i64], text: &str) -> &'a [i64] {
+    let tokenizer = generator.tokenizer();
+    let eos_token_id = generator.eos_token_id();
+    // EOS and the padding after it are not part of the text.
+    let end = generated
+        .iter()
+        .position(|token| *token == eos_token_id)
+        .unwrap_or(generated.len());
+    let mut generated = &generated[..end];
+    // Tokens after a stop sequence were cut from the text.
+    while let Some((_, rest)) = generated.split_last() {
+        if tokenizer.decode(rest, true, true).len() < text.len() {
+            break;
+        }
+        generated = rest;
+    }
+    generated
+}
+
+/// Scores `generated`, the tokens the model continued `context` with.
+fn choice_logprobs(
+    generator: &dyn Generator,
+    context: &str,
+    generated: &[i64],
+    top: u8,
+) -> Result<Vec<Logprob>, BertError> {
+    let tokenizer = generator.tokenizer();
+    let mut tokens = prompt_tokens(generator, context);
+    let start = tokens.len();
+    tokens.extend_from_slice(generated);
+    let logits = generator.logits(&tokens)?;
+    Ok(token_logprobs(
+        tokenizer,
+        &logits,
+        &tokens,
+        start,
+        usize::from(top),
+    ))
+}
+
+/// The tokens a causal model continues `context` from.
+fn prompt_tokens(generator: &dyn Generator, context: &str) -> Vec<i64> {
+    let tokenizer = generator.tokenizer();
+    let mut tokens = tokenizer.convert_tokens_to_ids(&tokenizer.tokenize(context));
+    if tokens.is_empty() {
+        // Causal models start an empty prompt from their EOS token.
+        tokens.push(generator.eos_token_id());
+    }
+    tokens
+}
+
+/// Runs `f` with the random number generator seeded. Seeded runs hold the
+/// generator exclusively, so concurrent generations cannot advance it.
+fn with_seed<T>(seed: Option<u64>, f: impl FnOnce() -> T) -> T {
+    match seed {
+        Some(seed) => {
+            let _rng = RNG.write().unwrap_or_else(PoisonError::into_inner);
+            tch::manual_seed(seed as i64);
+            f()
+        }
+        None => {
+            let _rng = RNG.read().unwrap_or_else(PoisonError::into_inner);
+            f()
+        }
+    }
+}
+
+/// Returns the byte offset of the earliest stop sequence in `text`.
+fn find_stop(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter_map(|sequence| text.find(sequence.as_str()))
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_stop() {
+        let stop = vec!["\n".to_string(), "END".to_string()];
+        assert_eq!(find_stop("Hello END world\n", &stop), Some(6));
+        assert_eq!(find_stop("Hello\nEND", &stop), Some(5));
+        assert_eq!(find_stop("Hello world", &stop), None);
+        assert_eq!(find_stop("Hello world", &[]), None);
+    }
+
+    #[test]
+    fn test_revision() {
+        let dir = std::env::temp_dir().join("bert-cli-test-revision");
+        fs::create_dir_all(&dir).unwrap();
+        let local_path = dir.join("4f1c.9d2e");
+        let resource = RemoteResource::from_pretrained((
+            "gpt-neo-125M/model",
+            "https://huggingface.co/EleutherAI/gpt-neo-125M/resolve/main/rust_model.ot",
+        ));
+        assert_eq!(
+            revision(&resource, &local_path),
+            "EleutherAI/gpt-neo-125M@4f1c.9d2e"
+        );
+        fs::write(
+            dir.join("4f1c.9d2e.meta"),
+            r#"{"resource": "https://huggingface.co/EleutherAI/gpt-neo-125M/resolve/main/rust_model.ot", "etag": "\"7b3a\""}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            revision(&resource, &local_path),
+            "EleutherAI/gpt-neo-125M@7b3a"
+        );
+        let resource = RemoteResource::new("https://example.com/rust_model.ot", "custom/model");
+        assert_eq!(
+            revision(&resource, &local_path),
+            "https://example.com/rust_model.ot@7b3a"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
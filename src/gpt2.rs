@@ -1,77 +1,367 @@
-use async_trait::async_trait;
-use rust_bert::gpt2::GPT2Generator;
-use rust_bert::pipelines::generation_utils::GenerateOptions;
-use rust_bert::pipelines::generation_utils::LanguageGenerator;
-use std::error;
+use rust_bert::gpt2::{
+    GPT2Generator, GPT2LMHeadModel, Gpt2Config, Gpt2ConfigResources, Gpt2MergesResources,
+    Gpt2ModelResources, Gpt2VocabResources,
+};
+use rust_bert::pipelines::common::TokenizerOption;
+use rust_bert::pipelines::generation_utils::{
+    GenerateConfig, GenerateOptions, GeneratedIndicesOutput, LanguageGenerator,
+};
+use rust_bert::resources::{RemoteResource, ResourceProvider};
+use rust_bert::{Config, RustBertError};
+use tch::nn::VarStore;
+use tch::{Device, Tensor};
 
-use crate::ai::AI;
+use crate::error::BertError;
+use crate::generation::{revision, Defaults, Generator};
+use crate::logprobs::Scorer;
 
 pub struct GPT2 {
     model: GPT2Generator,
+    vocab_size: i64,
+    eos_token_id: i64,
+    max_positions: i64,
+    defaults: Defaults,
+    revision: String,
+    scorer: Scorer<GPT2LMHeadModel>,
 }
 
 impl GPT2 {
-    pub fn new() -> Self {
-        let model = std::thread::spawn(move || GPT2Generator::new(Default::default()).unwrap())
-            .join()
-            .expect("Thread panicked");
-        GPT2 { model }
+    pub fn new(token_max_length: u16, temperature: f32, top_p: f32) -> Result<Self, BertError> {
+        let config_resource = Box::new(RemoteResource::from_pretrained(Gpt2ConfigResources::GPT2));
+        let vocab_resource = Box::new(RemoteResource::from_pretrained(Gpt2VocabResources::GPT2));
+        let merges_resource = Box::new(RemoteResource::from_pretrained(Gpt2MergesResources::GPT2));
+        let model_resource = Box::new(RemoteResource::from_pretrained(Gpt2ModelResources::GPT2));
+        let config_path = config_resource.get_local_path()?;
+        let config = Gpt2Config::from_file(&config_path);
+        let weights_path = model_resource.get_local_path()?;
+        let revision = revision(&model_resource, &weights_path);
+        let device = Device::cuda_if_available();
+        let scorer = Scorer::new(move || {
+            let config = Gpt2Config::from_file(&config_path);
+            let mut var_store = VarStore::new(device);
+            let model = GPT2LMHeadModel::new(&var_store.root(), &config);
+            var_store.load(&weights_path).map_err(RustBertError::from)?;
+            Ok((var_store, model))
+        });
+        let generate_config = GenerateConfig {
+            model_resource,
+            config_resource,
+            vocab_resource,
+            merges_resource,
+            max_length: token_max_length.into(),
+            do_sample: true,
+            early_stopping: false,
+            repetition_penalty: 1.1,
+            temperature: temperature.into(),
+            top_p: top_p.into(),
+            top_k: 10,
+            device,
+            ..Default::default()
+        };
+        let defaults = Defaults::from(&generate_config);
+        let model = GPT2Generator::new(generate_config)?;
+        let eos_token_id = model
+            .get_tokenizer()
+            .convert_tokens_to_ids(&["<|endoftext|>"])[0];
+        Ok(GPT2 {
+            model,
+            vocab_size: config.vocab_size,
+            eos_token_id,
+            max_positions: config.n_positions,
+            defaults,
+            revision,
+            scorer,
+        })
     }
 }
 
-unsafe impl Send for GPT2 {}
+impl Generator for GPT2 {
+    fn generate_indices(
+        &self,
+        prompts: &[String],
+        options: GenerateOptions,
+    ) -> Vec<GeneratedIndicesOutput> {
+        self.model.generate_indices(Some(prompts), Some(options))
+    }
 
-unsafe impl Sync for GPT2 {}
+    fn tokenizer(&self) -> &TokenizerOption {
+        self.model.get_tokenizer()
+    }
 
-#[async_trait]
-impl AI for GPT2 {
-    async fn response(
-        &self,
-        context: String,
-        token_max_length: u16,
-        temperature: f32,
-        top_p: f32,
-        _stop_sequence: Option<String>,
-    ) -> Result<String, Box<dyn error::Error>> {
-        let generate_options = GenerateOptions {
-            max_length: Some(token_max_length.into()),
-            do_sample: Some(true),
-            early_stopping: Some(false),
-            repetition_penalty: Some(1.1),
-            temperature: Some(temperature as f64),
-            top_p: Some(top_p as f64),
-            top_k: Some(10),
-            ..Default::default()
-        };
+    fn vocab_size(&self) -> i64 {
+        self.vocab_size
+    }
+
+    fn eos_token_id(&self) -> i64 {
+        self.eos_token_id
+    }
 
-        let output = self
-            .model
-            .generate(Some(&[context.to_string()]), Some(generate_options));
-        let response = output[0].text.to_string();
-        let response: String = response.replace(context.as_str(), "");
-        Ok(response)
+    fn max_positions(&self) -> i64 {
+        self.max_positions
     }
 
-    fn name(&self) -> String {
-        "gpt2".to_string()
+    fn defaults(&self) -> &Defaults {
+        &self.defaults
+    }
+
+    fn revision(&self) -> &str {
+        &self.revision
+    }
+
+    fn scores_tokens(&self) -> bool {
+        true
+    }
+
+    fn logits(&self, tokens: &[i64]) -> Result<Tensor, BertError> {
+        self.scorer.logits(tokens)
+    }
+
+    fn scorer_loaded(&self) -> bool {
+        self.scorer.is_loaded()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ai::AI;
+    use crate::batch::BatchConfig;
+    use crate::generation::FinishReason;
+    use crate::params::{GenerationParams, Truncation};
+    use crate::worker::Worker;
+    use std::collections::BTreeMap;
 
     #[tokio::test]
     async fn test_response() {
-        let ai = GPT2::new();
+        let ai = Worker::spawn("gpt2", BatchConfig::default(), || GPT2::new(42, 0.9, 0.9)).unwrap();
         let context = "Lots of Tesla cars to deliver before year end! Your support in taking delivery is much appreciated.".to_string();
+        let params = GenerationParams {
+            max_length: Some(42),
+            temperature: Some(0.9),
+            top_p: Some(0.9),
+            ..Default::default()
+        };
+        let output = ai
+            .response(context.to_string(), params)
+            .await
+            .unwrap()
+            .choices
+            .remove(0);
+        println!("{}", output.text);
+        assert_ne!(output.text, context);
+        assert_ne!(output.text.len(), 0);
+        assert!(output.text.len() > 10);
+        assert_eq!(ai.revision().as_deref(), Some("gpt2@main"));
+    }
+
+    #[tokio::test]
+    async fn test_stop_sequence() {
+        let ai = Worker::spawn("gpt2", BatchConfig::default(), || GPT2::new(64, 0.9, 0.9)).unwrap();
+        let context = "One, two, three, four,".to_string();
+        let params = GenerationParams {
+            stop: vec![",".to_string()],
+            ..Default::default()
+        };
+        let output = ai
+            .response(context, params)
+            .await
+            .unwrap()
+            .choices
+            .remove(0);
+        println!("{}", output.text);
+        assert!(!output.text.contains(','));
+    }
+
+    #[tokio::test]
+    async fn test_choices() {
+        let ai = Worker::spawn("gpt2", BatchConfig::default(), || GPT2::new(32, 0.9, 0.9)).unwrap();
+        let context = "The weather today is".to_string();
+        let params = GenerationParams {
+            n: Some(3),
+            ..Default::default()
+        };
+        let output = ai.response(context, params).await.unwrap();
+        assert_eq!(output.choices.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_seed() {
+        let ai = Worker::spawn("gpt2", BatchConfig::default(), || GPT2::new(32, 0.9, 0.9)).unwrap();
+        let context = "The weather today is".to_string();
+        let params = GenerationParams {
+            seed: Some(42),
+            ..Default::default()
+        };
+        let first = ai.response(context.clone(), params.clone()).await.unwrap();
+        let second = ai.response(context, params).await.unwrap();
+        assert_eq!(first.choices[0].text, second.choices[0].text);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let ai =
+            Worker::spawn("gpt2", BatchConfig::default(), || GPT2::new(200, 0.9, 0.9)).unwrap();
+        let params = GenerationParams {
+            max_length: Some(200),
+            timeout_ms: Some(1),
+            ..Default::default()
+        };
+        let output = ai
+            .response("Once upon a time".to_string(), params)
+            .await
+            .unwrap()
+            .choices
+            .remove(0);
+        assert_eq!(output.finish_reason, FinishReason::Timeout);
+    }
+
+    #[tokio::test]
+    async fn test_echo() {
+        let ai = Worker::spawn("gpt2", BatchConfig::default(), || GPT2::new(32, 0.9, 0.9)).unwrap();
+        let context = "Hello world.".to_string();
+        let params = GenerationParams {
+            echo: true,
+            ..Default::default()
+        };
+        let output = ai.response(context.clone(), params).await.unwrap();
+        assert!(output.choices[0].text.starts_with(&context));
+        assert!(output.choices[0].text.len() > context.len());
+    }
+
+    #[tokio::test]
+    async fn test_logprobs() {
+        let ai = Worker::spawn("gpt2", BatchConfig::default(), || GPT2::new(32, 0.9, 0.9)).unwrap();
+        let params = GenerationParams {
+            logprobs: Some(3),
+            ..Default::default()
+        };
         let output = ai
-            .response(context.to_string(), 42, 0.9, 4.0, None)
+            .response("The capital of France is".to_string(), params)
+            .await
+            .unwrap()
+            .choices
+            .remove(0);
+        let logprobs = output.logprobs.unwrap();
+        assert!(!logprobs.is_empty());
+        let text: String = logprobs
+            .iter()
+            .map(|logprob| logprob.token.as_str())
+            .collect();
+        assert!(output.text.starts_with(text.trim_end()));
+        for logprob in &logprobs {
+            assert!(logprob.logprob <= 0.0);
+            assert_eq!(logprob.top_logprobs.len(), 3);
+            assert!(logprob.top_logprobs[0].logprob >= logprob.logprob);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_constraints() {
+        let ai = Worker::spawn("gpt2", BatchConfig::default(), || GPT2::new(32, 0.9, 0.9)).unwrap();
+        let params = GenerationParams {
+            max_length: Some(32),
+            num_beams: Some(2),
+            bad_words: vec!["Paris".to_string()],
+            force_words: vec!["baguette".to_string()],
+            ..Default::default()
+        };
+        let output = ai
+            .response("The capital of France is".to_string(), params)
+            .await
+            .unwrap()
+            .choices
+            .remove(0);
+        assert!(!output.text.contains("Paris"));
+        assert!(output.text.contains("baguette"));
+    }
+
+    #[tokio::test]
+    async fn test_logit_bias() {
+        let ai = Worker::spawn("gpt2", BatchConfig::default(), || GPT2::new(32, 0.9, 0.9)).unwrap();
+        let params = GenerationParams {
+            max_length: Some(16),
+            num_beams: Some(3),
+            do_sample: Some(false),
+            logit_bias: BTreeMap::from([
+                (" Paris".to_string(), -100.0),
+                ("50256".to_string(), -100.0),
+            ]),
+            ..Default::default()
+        };
+        let generation = ai
+            .response("The capital of France is".to_string(), params)
             .await
             .unwrap();
-        println!("{}", output);
-        assert_ne!(output, context);
-        assert_ne!(output.len(), 0);
-        assert!(output.len() > 10);
+        assert!(!generation.choices[0].text.contains("Paris"));
+        assert_eq!(generation.choices[0].finish_reason, FinishReason::Length);
+        assert_eq!(generation.logit_bias.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_truncation() {
+        let ai = Worker::spawn("gpt2", BatchConfig::default(), || GPT2::new(32, 0.9, 0.9)).unwrap();
+        let context = "Hello world. ".repeat(400);
+        let params = GenerationParams {
+            max_new_tokens: Some(16),
+            ..Default::default()
+        };
+        let error = ai.response(context.clone(), params.clone()).await;
+        assert!(matches!(
+            error,
+            Err(BertError::TokenizationOverflow {
+                max_tokens: 1008,
+                ..
+            })
+        ));
+        let params = GenerationParams {
+            truncation: Truncation::Left,
+            ..params
+        };
+        let generation = ai.response(context, params.clone()).await.unwrap();
+        assert!(generation.usage.prompt_tokens <= 1008);
+        assert!(generation.usage.dropped_tokens >= 1200 - 1008);
+
+        // Far more than the backend's max_length of 32, which must not cut it.
+        let context = "Hello world. ".repeat(150);
+        let generation = ai.response(context, params).await.unwrap();
+        assert!(generation.usage.prompt_tokens >= 450);
+        assert_eq!(generation.usage.dropped_tokens, 0);
+        assert!(generation.usage.completion_tokens > 0);
+
+        let params = GenerationParams {
+            max_length: Some(32),
+            ..Default::default()
+        };
+        let error = ai.response("Hello world. ".repeat(20), params).await;
+        assert!(matches!(
+            error,
+            Err(BertError::TokenizationOverflow { max_tokens: 31, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_prompt_longer_than_max_length() {
+        let ai = Worker::spawn("gpt2", BatchConfig::default(), || GPT2::new(32, 0.9, 0.9)).unwrap();
+        let context = "Hello world. ".repeat(20);
+        let params = GenerationParams {
+            max_length: Some(32),
+            truncation: Truncation::Left,
+            echo: true,
+            ..Default::default()
+        };
+        let generation = ai.response(context.clone(), params).await.unwrap();
+        let choice = &generation.choices[0];
+        // Only the kept end of the prompt is echoed, once.
+        assert!(choice.text.len() < context.len());
+        assert!(generation.usage.prompt_tokens <= 31);
+        assert!(generation.usage.completion_tokens <= 1);
+
+        let params = GenerationParams {
+            max_length: Some(32),
+            truncation: Truncation::Left,
+            ..Default::default()
+        };
+        let generation = ai.response(context, params).await.unwrap();
+        assert!(!generation.choices[0].text.contains("Hello world."));
     }
 }
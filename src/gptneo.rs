@@ -1,21 +1,32 @@
-use async_trait::async_trait;
 use rust_bert::gpt_neo::{
-    GptNeoConfigResources, GptNeoMergesResources, GptNeoModelResources, GptNeoVocabResources,
+    GptNeoConfig, GptNeoConfigResources, GptNeoForCausalLM, GptNeoGenerator, GptNeoMergesResources,
+    GptNeoModelResources, GptNeoVocabResources,
 };
-use rust_bert::pipelines::common::ModelType;
-use rust_bert::pipelines::text_generation::{TextGenerationConfig, TextGenerationModel};
-use rust_bert::resources::RemoteResource;
-use std::error;
-use tch::Device;
+use rust_bert::pipelines::common::TokenizerOption;
+use rust_bert::pipelines::generation_utils::{
+    GenerateConfig, GenerateOptions, GeneratedIndicesOutput, LanguageGenerator,
+};
+use rust_bert::resources::{RemoteResource, ResourceProvider};
+use rust_bert::{Config, RustBertError};
+use tch::nn::VarStore;
+use tch::{Device, Tensor};
 
-use crate::ai::AI;
+use crate::error::BertError;
+use crate::generation::{revision, Defaults, Generator};
+use crate::logprobs::Scorer;
 
 pub struct GPTNeo {
-    model: TextGenerationModel,
+    model: GptNeoGenerator,
+    vocab_size: i64,
+    eos_token_id: i64,
+    max_positions: i64,
+    defaults: Defaults,
+    revision: String,
+    scorer: Scorer<GptNeoForCausalLM>,
 }
 
 impl GPTNeo {
-    pub fn new(token_max_length: u16, temperature: f32, top_p: f32) -> Self {
+    pub fn new(token_max_length: u16, temperature: f32, top_p: f32) -> Result<Self, BertError> {
         let config_resource = Box::new(RemoteResource::from_pretrained(
             GptNeoConfigResources::GPT_NEO_125M,
         ));
@@ -28,8 +39,19 @@ impl GPTNeo {
         let model_resource = Box::new(RemoteResource::from_pretrained(
             GptNeoModelResources::GPT_NEO_125M,
         ));
-        let generate_config = TextGenerationConfig {
-            model_type: ModelType::GPTNeo,
+        let config_path = config_resource.get_local_path()?;
+        let config = GptNeoConfig::from_file(&config_path);
+        let weights_path = model_resource.get_local_path()?;
+        let revision = revision(&model_resource, &weights_path);
+        let device = Device::cuda_if_available();
+        let scorer = Scorer::new(move || {
+            let config = GptNeoConfig::from_file(&config_path);
+            let mut var_store = VarStore::new(device);
+            let model = GptNeoForCausalLM::new(&var_store.root(), &config)?;
+            var_store.load(&weights_path).map_err(RustBertError::from)?;
+            Ok((var_store, model))
+        });
+        let generate_config = GenerateConfig {
             model_resource,
             config_resource,
             vocab_resource,
@@ -42,61 +64,100 @@ impl GPTNeo {
             temperature: temperature.into(),
             top_p: top_p.into(),
             top_k: 55,
-            device: Device::cuda_if_available(),
+            device,
             ..Default::default()
         };
 
-        let model = std::thread::spawn(move || {
-            let mut model = TextGenerationModel::new(generate_config).unwrap();
-            model.set_device(Device::cuda_if_available());
-            model
+        let defaults = Defaults::from(&generate_config);
+        let model = GptNeoGenerator::new(generate_config)?;
+        Ok(GPTNeo {
+            model,
+            vocab_size: config.vocab_size,
+            eos_token_id: config.eos_token_id,
+            max_positions: config.max_position_embeddings,
+            defaults,
+            revision,
+            scorer,
         })
-        .join()
-        .expect("Thread panicked");
-        GPTNeo { model }
     }
 }
 
-unsafe impl Send for GPTNeo {}
+impl Generator for GPTNeo {
+    fn generate_indices(
+        &self,
+        prompts: &[String],
+        options: GenerateOptions,
+    ) -> Vec<GeneratedIndicesOutput> {
+        self.model.generate_indices(Some(prompts), Some(options))
+    }
 
-unsafe impl Sync for GPTNeo {}
+    fn tokenizer(&self) -> &TokenizerOption {
+        self.model.get_tokenizer()
+    }
 
-#[async_trait]
-impl AI for GPTNeo {
-    async fn response(
-        &self,
-        context: String,
-        _token_max_length: u16,
-        _temperature: f32,
-        _top_p: f32,
-        _stop_sequence: Option<String>,
-    ) -> Result<String, Box<dyn error::Error>> {
-        let output = self.model.generate(&[context.to_string()], None);
-        let response = output[0].to_string();
-        let response = response.replace(context.as_str(), "");
-        Ok(response)
+    fn vocab_size(&self) -> i64 {
+        self.vocab_size
+    }
+
+    fn eos_token_id(&self) -> i64 {
+        self.eos_token_id
+    }
+
+    fn max_positions(&self) -> i64 {
+        self.max_positions
+    }
+
+    fn defaults(&self) -> &Defaults {
+        &self.defaults
+    }
+
+    fn revision(&self) -> &str {
+        &self.revision
+    }
+
+    fn scores_tokens(&self) -> bool {
+        true
     }
 
-    fn name(&self) -> String {
-        "gptneo".to_string()
+    fn logits(&self, tokens: &[i64]) -> Result<Tensor, BertError> {
+        self.scorer.logits(tokens)
+    }
+
+    fn scorer_loaded(&self) -> bool {
+        self.scorer.is_loaded()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ai::AI;
+    use crate::batch::BatchConfig;
+    use crate::params::GenerationParams;
+    use crate::worker::Worker;
 
     #[tokio::test]
     async fn test_response() {
-        let ai = GPTNeo::new(42, 1.1, 0.9);
+        let ai = Worker::spawn("gptneo", BatchConfig::default(), || {
+            GPTNeo::new(42, 1.1, 0.9)
+        })
+        .unwrap();
         let context = "Lots of Tesla cars to deliver before year end! Your support in taking delivery is much appreciated.".to_string();
+        let params = GenerationParams {
+            max_length: Some(42),
+            temperature: Some(1.1),
+            top_p: Some(0.9),
+            ..Default::default()
+        };
         let output = ai
-            .response(context.to_string(), 42, 1.1, 0.9, None)
+            .response(context.to_string(), params)
             .await
-            .unwrap();
-        println!("{}", output);
-        assert_ne!(output, context);
-        assert_ne!(output.len(), 0);
-        assert!(output.len() > 10);
+            .unwrap()
+            .choices
+            .remove(0);
+        println!("{}", output.text);
+        assert_ne!(output.text, context);
+        assert_ne!(output.text.len(), 0);
+        assert!(output.text.len() > 10);
     }
 }
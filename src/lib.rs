@@ -1,90 +1,545 @@
+use rocket_okapi::okapi::schemars;
+use rocket_okapi::okapi::schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::error;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{Mutex, Notify, OnceCell};
 
 mod ai;
 mod bart;
+mod batch;
+mod bias;
+mod cache;
+mod classify;
+mod constraints;
+mod error;
+mod generation;
 mod gpt2;
 mod gptneo;
 mod gptneo1;
 mod gptneo2;
+mod logprobs;
 mod m2m100;
 mod mbart;
+mod params;
 mod prophetnet;
+mod registry;
+mod sentiment;
+mod summarize;
+mod translate;
+mod worker;
+
+pub use ai::AI;
+pub use batch::{BatchConfig, BatchMetrics};
+pub use bias::TokenBias;
+pub use cache::{ModelKind, ModelStatus};
+pub use classify::{Classifier, ClassifyOptions, DEFAULT_HYPOTHESIS_TEMPLATE};
+pub use error::BertError;
+pub use generation::{Choice, FinishReason, Generation, GenerationStream, Usage};
+pub use logprobs::{Logprob, TokenLogprob};
+pub use params::{GenerationParams, Truncation};
+pub use registry::{ModelEntry, ModelLoader, ModelRegistry};
+pub use sentiment::{Polarity, SentimentAnalyzer};
+pub use summarize::Summarizer;
+pub use translate::{Translator, TRANSLATION_MODELS};
+
+use cache::ModelCache;
+use worker::ModelThread;
 
 pub fn create_ai(
     ai: String,
     token_max_length: u16,
     temperature: f32,
     top_p: f32,
-) -> Box<dyn ai::AI> {
-    match ai.as_str() {
-        "bart" => Box::new(bart::Bart::new(token_max_length, temperature, top_p)),
-        "mbart" => Box::new(mbart::MBart::new(token_max_length, temperature, top_p)),
-        "m2m100" => Box::new(m2m100::M2M100::new(token_max_length, temperature, top_p)),
-        "gpt2" => Box::new(gpt2::GPT2::new()),
-        "gptneo" => Box::new(gptneo::GPTNeo::new(token_max_length, temperature, top_p)),
-        "gptneo1" => Box::new(gptneo1::GPTNeo1::new(token_max_length, temperature, top_p)),
-        "gptneo2" => Box::new(gptneo2::GPTNeo2::new(token_max_length, temperature, top_p)),
-        "prophetnet" => Box::new(prophetnet::ProphetNet::new(
-            token_max_length,
-            temperature,
-            top_p,
-        )),
-        _ => Box::new(gptneo::GPTNeo::new(token_max_length, temperature, top_p)),
-    }
+) -> Result<Box<dyn AI>, BertError> {
+    ModelRegistry::default().load(
+        &ai,
+        token_max_length,
+        temperature,
+        top_p,
+        BatchConfig::default(),
+    )
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Labels of a sequence, sorted by descending score.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ClassifyResponse {
     pub sequence: String,
     pub labels: Vec<String>,
     pub scores: Vec<f32>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SentimentResponse {
+    pub text: String,
+    pub polarity: Polarity,
+    /// Confidence in the polarity, between 0.5 and 1.
+    pub score: f32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GenerateResponse {
     pub model: String,
-    pub text: String,
+    pub revision: Option<String>,
+    pub choices: Vec<Choice>,
     pub prompt: String,
+    pub seed: Option<u64>,
+    pub usage: Usage,
+    pub logit_bias: Vec<TokenBias>,
+}
+
+/// Memory held for a load in progress, given up when dropped, whether the load
+/// succeeded, failed or was abandoned.
+struct Reservation<'a> {
+    bert: &'a Bert,
+    memory_mb: u64,
 }
 
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.bert
+            .reserved_mb
+            .fetch_sub(self.memory_mb, Ordering::SeqCst);
+        self.bert.released.notify_waiters();
+    }
+}
+
+/// A generation request with its model selected, see `Bert::prepare`.
+pub struct PreparedRequest<'a> {
+    bert: &'a Bert,
+    ai: Arc<dyn AI>,
+    context: String,
+    params: GenerationParams,
+}
+
+impl PreparedRequest<'_> {
+    /// Dropping the returned future cancels the generation at its next decoding step.
+    pub async fn generate(self) -> Result<GenerateResponse, BertError> {
+        let seed = self.params.seed;
+        let result = self.ai.response(self.context.clone(), self.params).await;
+        if self.ai.scorer_loaded() {
+            self.bert.fit_budget(&self.ai.name()).await;
+        }
+        let result = result?;
+        Ok(GenerateResponse {
+            model: self.ai.name(),
+            revision: self.ai.revision(),
+            choices: result.choices,
+            prompt: self.context,
+            seed,
+            usage: result.usage,
+            logit_bias: result.logit_bias,
+        })
+    }
+}
+
+/// Serves generation requests from one or more models, loaded on first use.
 pub struct Bert {
-    pub ai: Box<dyn ai::AI>,
+    registry: Arc<ModelRegistry>,
+    cache: Mutex<ModelCache>,
+    /// Held while the model of the same name loads.
+    loading: BTreeMap<String, Mutex<()>>,
+    /// Memory reserved by loads in progress.
+    reserved_mb: AtomicU64,
+    /// Notified when a load gives up its reservation.
+    released: Notify,
+    default_model: String,
+    token_max_length: u16,
+    temperature: f32,
+    top_p: f32,
+    batch: BatchConfig,
+    timeout_ms: Option<u64>,
+    classifier: OnceCell<ModelThread<Classifier>>,
+    summarizer: OnceCell<ModelThread<Summarizer>>,
+    sentiment: OnceCell<ModelThread<SentimentAnalyzer>>,
 }
 
 impl Bert {
-    pub async fn generate(
-        &self,
-        context: String,
+    pub fn new(ai: Box<dyn AI>) -> Self {
+        let registry = ModelRegistry::default();
+        let default_model = ai.name();
+        let memory_mb = registry
+            .resolve(&default_model)
+            .map(|entry| entry.memory_mb)
+            .unwrap_or(0);
+        let mut cache = ModelCache::new(None);
+        cache.insert(&default_model, memory_mb, Arc::from(ai));
+        add_translators(&mut cache, &registry);
+        Bert {
+            registry: Arc::new(registry),
+            loading: loading(&cache),
+            reserved_mb: AtomicU64::new(0),
+            released: Notify::new(),
+            cache: Mutex::new(cache),
+            default_model,
+            token_max_length: 100,
+            temperature: 1.1,
+            top_p: 0.9,
+            batch: BatchConfig::default(),
+            timeout_ms: None,
+            classifier: OnceCell::new(),
+            summarizer: OnceCell::new(),
+            sentiment: OnceCell::new(),
+        }
+    }
+
+    /// Serves every model in `names` from `registry`. `default_model` answers
+    /// requests that do not name a model and is served even if not listed.
+    /// Models are loaded on first use.
+    pub fn with_models(
+        registry: ModelRegistry,
+        names: &[String],
+        default_model: &str,
         token_max_length: u16,
         temperature: f32,
         top_p: f32,
-        stop_sequence: Option<String>,
-    ) -> Result<GenerateResponse, Box<dyn error::Error>> {
-        let result = self
-            .ai
-            .response(
-                context.to_string(),
-                token_max_length,
-                temperature,
-                top_p,
-                stop_sequence,
-            )
+    ) -> Result<Self, BertError> {
+        let default_model = registry.resolve(default_model)?.name.clone();
+        let mut cache = ModelCache::new(None);
+        for name in [default_model.as_str()]
+            .into_iter()
+            .chain(names.iter().map(String::as_str))
+        {
+            let entry = registry.resolve(name)?;
+            cache.add(&entry.name, entry.memory_mb);
+        }
+        add_translators(&mut cache, &registry);
+        Ok(Bert {
+            registry: Arc::new(registry),
+            loading: loading(&cache),
+            reserved_mb: AtomicU64::new(0),
+            released: Notify::new(),
+            cache: Mutex::new(cache),
+            default_model,
+            token_max_length,
+            temperature,
+            top_p,
+            batch: BatchConfig::default(),
+            timeout_ms: None,
+            classifier: OnceCell::new(),
+            summarizer: OnceCell::new(),
+            sentiment: OnceCell::new(),
+        })
+    }
+
+    /// Evicts least recently used models once resident models would exceed `budget_mb`.
+    pub fn set_memory_budget(&mut self, budget_mb: Option<u64>) {
+        self.cache.get_mut().set_budget(budget_mb);
+    }
+
+    /// Batching used by models loaded from now on.
+    pub fn set_batching(&mut self, batch: BatchConfig) {
+        self.batch = batch;
+    }
+
+    /// Default timeout for every request. Requests may only ask for a shorter one.
+    pub fn set_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    /// Status of every servable model, default model first.
+    pub async fn models(&self) -> Vec<ModelStatus> {
+        self.cache.lock().await.status()
+    }
+
+    async fn select(&self, model: Option<&str>) -> Result<Arc<dyn AI>, BertError> {
+        let entry = match model {
+            Some(model) => self.registry.resolve(model)?,
+            None => self.registry.resolve(&self.default_model)?,
+        };
+        let resident = |cache: &mut ModelCache| {
+            if !cache.contains(&entry.name) {
+                return Err(BertError::UnknownModel {
+                    name: entry.name.clone(),
+                    suggestions: cache.names(),
+                });
+            }
+            Ok(cache.get(&entry.name))
+        };
+        let ai = resident(&mut *self.cache.lock().await)?;
+        if let Some(ai) = ai {
+            // Catches scorers loaded by streams, which `generate` does not see finish.
+            self.fit_budget(&entry.name).await;
+            return Ok(ai);
+        }
+        // Loads of the same model wait for each other, everything else goes on.
+        let _loading = self
+            .loading
+            .get(&entry.name)
+            .expect("every servable model has a load guard")
+            .lock()
+            .await;
+        if let Some(ai) = resident(&mut *self.cache.lock().await)? {
+            return Ok(ai);
+        }
+
+        let _reservation = self.reserve(entry.memory_mb).await;
+        println!("Loading model {}.", entry.name);
+        let registry = self.registry.clone();
+        let name = entry.name.clone();
+        let (token_max_length, temperature, top_p, batch) = (
+            self.token_max_length,
+            self.temperature,
+            self.top_p,
+            self.batch,
+        );
+        let ai = tokio::task::spawn_blocking(move || {
+            registry.load(&name, token_max_length, temperature, top_p, batch)
+        })
+        .await
+        .map_err(|err| BertError::ModelLoad(err.to_string()))??;
+        let ai: Arc<dyn AI> = Arc::from(ai);
+        println!("Model {} loaded.", entry.name);
+        self.cache
+            .lock()
             .await
-            .unwrap();
+            .insert(&entry.name, entry.memory_mb, ai.clone());
+        Ok(ai)
+    }
+
+    /// Evicts models other than `keep` once scorers loaded for `logprobs` have
+    /// pushed the resident models over the budget.
+    async fn fit_budget(&self, keep: &str) {
+        for evicted in self.cache.lock().await.evict_over_budget(keep) {
+            println!("Model {} evicted.", evicted);
+        }
+    }
 
-        let gr = GenerateResponse {
-            model: "".to_string(),
-            text: result,
-            prompt: context,
+    /// Makes room for a model of `memory_mb` to load, evicting least recently
+    /// used models. While other loads hold reservations it cannot fit next to,
+    /// waits for them to finish, so concurrent loads never exceed the budget.
+    async fn reserve(&self, memory_mb: u64) -> Reservation<'_> {
+        loop {
+            // Created before checking, so a release in between is not missed.
+            let released = self.released.notified();
+            {
+                let mut cache = self.cache.lock().await;
+                let reserved_mb = self.reserved_mb.load(Ordering::SeqCst);
+                if let Some(evicted) = cache.evict_for_load(memory_mb, reserved_mb) {
+                    for evicted in evicted {
+                        println!("Model {} evicted.", evicted);
+                    }
+                    // Reservations are only added under the cache lock.
+                    self.reserved_mb.fetch_add(memory_mb, Ordering::SeqCst);
+                    return Reservation {
+                        bert: self,
+                        memory_mb,
+                    };
+                }
+            }
+            released.await;
+        }
+    }
+
+    fn limit_timeout(&self, mut params: GenerationParams) -> GenerationParams {
+        params.timeout_ms = match (params.timeout_ms, self.timeout_ms) {
+            (Some(requested), Some(limit)) => Some(requested.min(limit)),
+            (requested, limit) => requested.or(limit),
         };
-        Ok(gr)
+        params
+    }
+
+    /// Dropping the returned future cancels the generation at its next decoding step.
+    pub async fn generate(
+        &self,
+        model: Option<&str>,
+        context: String,
+        params: GenerationParams,
+    ) -> Result<GenerateResponse, BertError> {
+        self.request(model, context, params).await?.generate().await
+    }
+
+    /// Validates a request, loads its model and checks that its prompt fits,
+    /// so that `generate` on the result only fails while generating.
+    pub async fn prepare(
+        &self,
+        model: Option<&str>,
+        context: String,
+        params: GenerationParams,
+    ) -> Result<PreparedRequest<'_>, BertError> {
+        let request = self.request(model, context, params).await?;
+        request
+            .ai
+            .check(request.context.clone(), request.params.clone())
+            .await?;
+        Ok(request)
+    }
+
+    async fn request(
+        &self,
+        model: Option<&str>,
+        context: String,
+        params: GenerationParams,
+    ) -> Result<PreparedRequest<'_>, BertError> {
+        params.validate()?;
+        let params = self.limit_timeout(params);
+        let ai = self.select(model).await?;
+        Ok(PreparedRequest {
+            bert: self,
+            ai,
+            context,
+            params,
+        })
+    }
+
+    /// Like `generate`, but yields text as it is decoded. Beam search is not supported.
+    /// Dropping the stream cancels the generation.
+    pub async fn stream(
+        &self,
+        model: Option<&str>,
+        context: String,
+        params: GenerationParams,
+    ) -> Result<GenerationStream, BertError> {
+        params.validate()?;
+        if params.beam_search() {
+            return Err(BertError::InvalidParameters(
+                "streaming does not support beam search".to_string(),
+            ));
+        }
+        let params = self.limit_timeout(params);
+        let ai = self.select(model).await?;
+        let seed = params.seed;
+        Ok(ai.stream(context, params).await?.with_seed(seed))
+    }
+
+    /// Classifies every sequence into `labels`. The classifier is loaded on
+    /// first use and is not subject to the memory budget.
+    pub async fn classify(
+        &self,
+        sequences: Vec<String>,
+        labels: Vec<String>,
+        options: ClassifyOptions,
+    ) -> Result<Vec<ClassifyResponse>, BertError> {
+        options.validate(&labels)?;
+        let classifier = pipeline(&self.classifier, "classifier", Classifier::new).await?;
+        classifier
+            .run(move |classifier| classifier.classify(&sequences, &labels, &options))
+            .await
     }
+
+    /// Summarizes every text with BART-CNN. The summarizer is loaded on first
+    /// use and is not subject to the memory budget.
+    pub async fn summarize(&self, texts: Vec<String>) -> Result<Vec<String>, BertError> {
+        let summarizer = pipeline(&self.summarizer, "summarizer", Summarizer::new).await?;
+        summarizer
+            .run(move |summarizer| summarizer.summarize(&texts))
+            .await
+    }
+
+    /// Scores the sentiment of every text. The analyzer is loaded on first use
+    /// and is not subject to the memory budget.
+    pub async fn sentiment(&self, texts: Vec<String>) -> Result<Vec<SentimentResponse>, BertError> {
+        let analyzer = pipeline(&self.sentiment, "sentiment", SentimentAnalyzer::new).await?;
+        analyzer.run(move |analyzer| analyzer.analyze(&texts)).await
+    }
+
+    /// Translates every text from `source_lang` into `target_lang` with `model`,
+    /// mBART-50 by default. Translators hold their own copy of the model, are
+    /// loaded on first use and are evicted like models to fit the memory budget.
+    pub async fn translate(
+        &self,
+        model: Option<&str>,
+        texts: Vec<String>,
+        source_lang: String,
+        target_lang: String,
+    ) -> Result<Vec<String>, BertError> {
+        let entry = self
+            .registry
+            .resolve(model.unwrap_or(TRANSLATION_MODELS[0]))?;
+        // Unsupported models and languages fail before anything is loaded.
+        Translator::check(&entry.name, &source_lang, &target_lang)?;
+        let translator = self.translator(&entry.name, entry.memory_mb).await?;
+        translator
+            .run(move |translator| translator.translate(&texts, &source_lang, &target_lang))
+            .await
+    }
+
+    /// The translation pipeline of `model`, loaded like `select` loads models.
+    async fn translator(
+        &self,
+        model: &str,
+        memory_mb: u64,
+    ) -> Result<Arc<ModelThread<Translator>>, BertError> {
+        let slot = translator_slot(model);
+        if let Some(translator) = self.cache.lock().await.get_translator(&slot) {
+            return Ok(translator);
+        }
+        let _loading = self
+            .loading
+            .get(&slot)
+            .expect("every translator has a load guard")
+            .lock()
+            .await;
+        if let Some(translator) = self.cache.lock().await.get_translator(&slot) {
+            return Ok(translator);
+        }
+
+        let _reservation = self.reserve(memory_mb).await;
+        println!("Loading {} pipeline.", slot);
+        let (name, model) = (slot.clone(), model.to_string());
+        let translator = tokio::task::spawn_blocking(move || {
+            ModelThread::spawn(&name, move || Translator::new(&model))
+        })
+        .await
+        .map_err(|err| BertError::ModelLoad(err.to_string()))??;
+        let translator = Arc::new(translator);
+        println!("Pipeline {} loaded.", slot);
+        self.cache
+            .lock()
+            .await
+            .insert_translator(&slot, memory_mb, translator.clone());
+        Ok(translator)
+    }
+}
+
+fn loading(cache: &ModelCache) -> BTreeMap<String, Mutex<()>> {
+    cache
+        .slot_names()
+        .into_iter()
+        .map(|name| (name, Mutex::new(())))
+        .collect()
+}
+
+/// Name of the cache slot of the translation pipeline of `model`.
+fn translator_slot(model: &str) -> String {
+    format!("{}-translation", model)
+}
+
+/// Adds a slot for every translation model `registry` knows.
+fn add_translators(cache: &mut ModelCache, registry: &ModelRegistry) {
+    for model in TRANSLATION_MODELS {
+        if let Ok(entry) = registry.resolve(model) {
+            cache.add_translator(&translator_slot(&entry.name), entry.memory_mb);
+        }
+    }
+}
+
+/// The pipeline thread in `cell`, started by the first caller.
+async fn pipeline<'a, M, F>(
+    cell: &'a OnceCell<ModelThread<M>>,
+    name: &'static str,
+    load: F,
+) -> Result<&'a ModelThread<M>, BertError>
+where
+    M: 'static,
+    F: FnOnce() -> Result<M, BertError> + Send + 'static,
+{
+    cell.get_or_try_init(|| async {
+        println!("Loading {} pipeline.", name);
+        let thread = tokio::task::spawn_blocking(move || ModelThread::spawn(name, load))
+            .await
+            .map_err(|err| BertError::ModelLoad(err.to_string()))??;
+        println!("Pipeline {} loaded.", name);
+        Ok(thread)
+    })
+    .await
 }
 
 impl Default for Bert {
     fn default() -> Self {
-        let ai = create_ai("".to_string(), 100, 1.1, 0.9);
-        Bert { ai }
+        let ai =
+            create_ai("gptneo".to_string(), 100, 1.1, 0.9).expect("failed to load default model");
+        Bert::new(ai)
     }
 }
@@ -0,0 +1,132 @@
+use rocket_okapi::okapi::schemars;
+use rocket_okapi::okapi::schemars::JsonSchema;
+use rust_bert::pipelines::common::TokenizerOption;
+use rust_bert::pipelines::generation_utils::{Cache, LMHeadModel};
+use serde::{Deserialize, Serialize};
+use std::cell::OnceCell;
+use tch::nn::VarStore;
+use tch::{Device, Kind, Tensor};
+
+use crate::error::BertError;
+
+/// Most alternatives reported per token.
+pub const MAX_LOGPROBS: u8 = 20;
+
+/// A token and its log-probability under the model, before temperature, top-k
+/// and top-p are applied.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub token_id: i64,
+    pub logprob: f64,
+}
+
+/// A generated token with the most likely tokens at its position.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Logprob {
+    pub token: String,
+    pub token_id: i64,
+    pub logprob: f64,
+    /// Most likely first.
+    pub top_logprobs: Vec<TokenLogprob>,
+}
+
+type Load<M> = Box<dyn Fn() -> Result<(VarStore, M), BertError>>;
+
+/// A second copy of a causal language model that scores generated tokens.
+/// rust-bert's generators keep their model private, so the weights are loaded
+/// again, by the first request that asks for logprobs. The cache counts the
+/// copy against the memory budget once loaded, see `AI::scorer_loaded`.
+pub(crate) struct Scorer<M> {
+    load: Load<M>,
+    loaded: OnceCell<(VarStore, M)>,
+}
+
+impl<M: LMHeadModel> Scorer<M> {
+    pub fn new<F>(load: F) -> Self
+    where
+        F: Fn() -> Result<(VarStore, M), BertError> + 'static,
+    {
+        Scorer {
+            load: Box::new(load),
+            loaded: OnceCell::new(),
+        }
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.loaded.get().is_some()
+    }
+
+    fn loaded(&self) -> Result<&(VarStore, M), BertError> {
+        if self.loaded.get().is_none() {
+            let _ = self.loaded.set((self.load)()?);
+        }
+        Ok(self.loaded.get().expect("scorer loaded"))
+    }
+
+    /// Next-token logits at every position of `tokens`, as a float tensor on
+    /// the CPU of shape (*sequence_length*, *vocab_size*).
+    pub fn logits(&self, tokens: &[i64]) -> Result<Tensor, BertError> {
+        let (var_store, model) = self.loaded()?;
+        let input = Tensor::of_slice(tokens).unsqueeze(0).to(var_store.device());
+        let output = tch::no_grad(|| {
+            model.forward_t(
+                Some(&input),
+                Cache::None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                false,
+            )
+        })
+        .map_err(|err| BertError::Inference(err.to_string()))?;
+        Ok(output
+            .lm_logits
+            .squeeze_dim(0)
+            .to_kind(Kind::Float)
+            .to_device(Device::Cpu))
+    }
+}
+
+/// Log-probabilities of `tokens[start..]`, each with its `top` most likely
+/// alternatives, from the `logits` of `tokens`. `start` must be at least 1.
+pub(crate) fn token_logprobs(
+    tokenizer: &TokenizerOption,
+    logits: &Tensor,
+    tokens: &[i64],
+    start: usize,
+    top: usize,
+) -> Vec<Logprob> {
+    // Logits at position `i` predict token `i + 1`.
+    let logprobs = logits.log_softmax(-1, Kind::Float);
+    let token = |token_id: i64| tokenizer.decode(&[token_id], false, false);
+    (start..tokens.len())
+        .map(|position| {
+            let row = logprobs.get(position as i64 - 1);
+            let token_id = tokens[position];
+            let top_logprobs = if top > 0 {
+                let (values, indices) = row.topk(top as i64, -1, true, true);
+                Vec::<f64>::from(&values)
+                    .into_iter()
+                    .zip(Vec::<i64>::from(&indices))
+                    .map(|(logprob, token_id)| TokenLogprob {
+                        token: token(token_id),
+                        token_id,
+                        logprob,
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            Logprob {
+                token: token(token_id),
+                token_id,
+                logprob: row.double_value(&[token_id]),
+                top_logprobs,
+            }
+        })
+        .collect()
+}
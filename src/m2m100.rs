@@ -1,23 +1,29 @@
-use async_trait::async_trait;
 use rust_bert::m2m_100::{
-    M2M100ConfigResources, M2M100Generator, M2M100MergesResources, M2M100ModelResources,
-    M2M100VocabResources,
+    M2M100Config, M2M100ConfigResources, M2M100Generator, M2M100MergesResources,
+    M2M100ModelResources, M2M100VocabResources,
 };
-use rust_bert::pipelines::generation_utils::GenerateConfig;
-use rust_bert::pipelines::generation_utils::GenerateOptions;
-use rust_bert::pipelines::generation_utils::LanguageGenerator;
-use rust_bert::resources::RemoteResource;
-use std::error;
+use rust_bert::pipelines::common::TokenizerOption;
+use rust_bert::pipelines::generation_utils::{
+    GenerateConfig, GenerateOptions, GeneratedIndicesOutput, LanguageGenerator,
+};
+use rust_bert::resources::{RemoteResource, ResourceProvider};
+use rust_bert::Config;
 use tch::Device;
 
-use crate::ai::AI;
+use crate::error::BertError;
+use crate::generation::{revision, Defaults, Generator};
 
 pub struct M2M100 {
     model: M2M100Generator,
+    vocab_size: i64,
+    eos_token_id: i64,
+    max_positions: i64,
+    defaults: Defaults,
+    revision: String,
 }
 
 impl M2M100 {
-    pub fn new(token_max_length: u16, temperature: f32, top_p: f32) -> Self {
+    pub fn new(token_max_length: u16, temperature: f32, top_p: f32) -> Result<Self, BertError> {
         let model_resource = Box::new(RemoteResource::from_pretrained(
             M2M100ModelResources::M2M100_1_2B,
         ));
@@ -31,6 +37,8 @@ impl M2M100 {
             M2M100MergesResources::M2M100_1_2B,
         ));
         let device = Device::cuda_if_available();
+        let config = M2M100Config::from_file(config_resource.get_local_path()?);
+        let revision = revision(&model_resource, &model_resource.get_local_path()?);
         let generate_config = GenerateConfig {
             model_resource,
             config_resource,
@@ -38,74 +46,98 @@ impl M2M100 {
             merges_resource,
             max_length: token_max_length.into(),
             top_p: top_p.into(),
+            top_k: 10,
             do_sample: true,
+            early_stopping: false,
+            repetition_penalty: 1.1,
             num_beams: 5,
             temperature: temperature.into(),
             num_return_sequences: 3,
             device,
             ..Default::default()
         };
-        let model = std::thread::spawn(move || M2M100Generator::new(generate_config).unwrap())
-            .join()
-            .expect("Thread panicked");
+        let defaults = Defaults::from(&generate_config);
+        let model = M2M100Generator::new(generate_config)?;
 
-        M2M100 { model }
+        Ok(M2M100 {
+            model,
+            vocab_size: config.vocab_size,
+            eos_token_id: config.eos_token_id.unwrap_or(2),
+            max_positions: config.max_position_embeddings,
+            defaults,
+            revision,
+        })
     }
 }
 
-unsafe impl Send for M2M100 {}
+impl Generator for M2M100 {
+    fn generate_indices(
+        &self,
+        prompts: &[String],
+        options: GenerateOptions,
+    ) -> Vec<GeneratedIndicesOutput> {
+        self.model.generate_indices(Some(prompts), Some(options))
+    }
 
-unsafe impl Sync for M2M100 {}
+    fn tokenizer(&self) -> &TokenizerOption {
+        self.model.get_tokenizer()
+    }
 
-#[async_trait]
-impl AI for M2M100 {
-    async fn response(
-        &self,
-        context: String,
-        token_max_length: u16,
-        temperature: f32,
-        top_p: f32,
-        _stop_sequence: Option<String>,
-    ) -> Result<String, Box<dyn error::Error>> {
-        let generate_options = GenerateOptions {
-            max_length: Some(token_max_length.into()),
-            do_sample: Some(true),
-            early_stopping: Some(false),
-            repetition_penalty: Some(1.1),
-            temperature: Some(temperature.into()),
-            top_p: Some(top_p.into()),
-            top_k: Some(10),
-            ..Default::default()
-        };
+    fn vocab_size(&self) -> i64 {
+        self.vocab_size
+    }
 
-        let output = self
-            .model
-            .generate(Some(&[context.to_string()]), Some(generate_options));
-        let response = output[0].text.to_string();
-        let response: String = response.replace(context.as_str(), "");
-        Ok(response)
+    fn eos_token_id(&self) -> i64 {
+        self.eos_token_id
     }
 
-    fn name(&self) -> String {
-        "m2m100".to_string()
+    fn max_positions(&self) -> i64 {
+        self.max_positions
+    }
+
+    fn defaults(&self) -> &Defaults {
+        &self.defaults
+    }
+
+    fn is_encoder_decoder(&self) -> bool {
+        true
+    }
+
+    fn revision(&self) -> &str {
+        &self.revision
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ai::AI;
+    use crate::batch::BatchConfig;
+    use crate::params::GenerationParams;
+    use crate::worker::Worker;
 
     #[tokio::test]
     async fn test_response() {
-        let ai = M2M100::new(42, 0.9, 1.1);
+        let ai = Worker::spawn("m2m100", BatchConfig::default(), || {
+            M2M100::new(42, 0.9, 0.9)
+        })
+        .unwrap();
         let context = "Lots of Tesla cars to deliver before year end! Your support in taking delivery is much appreciated.".to_string();
+        let params = GenerationParams {
+            max_length: Some(42),
+            temperature: Some(0.9),
+            top_p: Some(0.9),
+            ..Default::default()
+        };
         let output = ai
-            .response(context.to_string(), 42, 0.9, 4.0, None)
+            .response(context.to_string(), params)
             .await
-            .unwrap();
-        println!("{}", output);
-        assert_ne!(output, context);
-        assert_ne!(output.len(), 0);
-        assert!(output.len() > 10);
+            .unwrap()
+            .choices
+            .remove(0);
+        println!("{}", output.text);
+        assert_ne!(output.text, context);
+        assert_ne!(output.text.len(), 0);
+        assert!(output.text.len() > 10);
     }
 }
@@ -0,0 +1,363 @@
+use rocket::form::FromFormField;
+use rocket_okapi::okapi::schemars;
+use rocket_okapi::okapi::schemars::JsonSchema;
+use rust_bert::pipelines::generation_utils::GenerateOptions;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::bias::MAX_BIAS;
+use crate::error::BertError;
+use crate::logprobs::MAX_LOGPROBS;
+
+/// What to do with a prompt that leaves no room for `max_new_tokens`.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema, FromFormField,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Truncation {
+    /// Fail with a tokenization overflow.
+    #[default]
+    Error,
+    /// Drop tokens from the start of the prompt.
+    Left,
+    /// Drop tokens from the end of the prompt.
+    Right,
+}
+
+impl FromStr for Truncation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "error" => Ok(Truncation::Error),
+            "left" => Ok(Truncation::Left),
+            "right" => Ok(Truncation::Right),
+            _ => Err(format!(
+                "unknown truncation {:?}, expected error, left or right",
+                value
+            )),
+        }
+    }
+}
+
+/// Sampling parameters for a single generation request.
+///
+/// Every knob is optional: a value left as `None` falls back to the setting
+/// the backend was created with.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationParams {
+    pub max_length: Option<u16>,
+    /// Tokens to generate after the prompt, instead of a `max_length` that
+    /// includes it.
+    pub max_new_tokens: Option<u16>,
+    /// Trims prompts too long for the model and `max_new_tokens`.
+    #[serde(default)]
+    pub truncation: Truncation,
+    pub min_length: Option<u16>,
+    pub do_sample: Option<bool>,
+    pub early_stopping: Option<bool>,
+    pub num_beams: Option<u16>,
+    pub num_beam_groups: Option<u16>,
+    pub num_return_sequences: Option<u16>,
+    /// Number of completions to return, sets `num_return_sequences`.
+    pub n: Option<u16>,
+    pub temperature: Option<f32>,
+    pub top_k: Option<u16>,
+    pub top_p: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub length_penalty: Option<f32>,
+    pub no_repeat_ngram_size: Option<u16>,
+    pub diversity_penalty: Option<f32>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Seeds the random number generator, making sampling reproducible.
+    pub seed: Option<u64>,
+    /// Stops generation after this many milliseconds, returning the text so far.
+    pub timeout_ms: Option<u64>,
+    /// Returns the prompt followed by the continuation.
+    #[serde(default)]
+    pub echo: bool,
+    /// Reports the log-probability of every generated token along with this
+    /// many of the most likely alternatives.
+    pub logprobs: Option<u8>,
+    /// Words that must never be generated, banned as token sequences.
+    #[serde(default)]
+    pub bad_words: Vec<String>,
+    /// Phrases every completion must contain, written out just in time before
+    /// `max_length` where the model has not generated them.
+    #[serde(default)]
+    pub force_words: Vec<String>,
+    /// Added to the logits of tokens, keyed by token id or by text whose every
+    /// token gets the bias. Only bans, biases of -100 or less, are supported.
+    #[serde(default)]
+    pub logit_bias: BTreeMap<String, f32>,
+}
+
+impl GenerationParams {
+    pub fn validate(&self) -> Result<(), BertError> {
+        if let Some(max_length) = self.max_length {
+            if max_length == 0 {
+                return Err(BertError::InvalidParameters(
+                    "max_length must be greater than 0".to_string(),
+                ));
+            }
+            if let Some(min_length) = self.min_length {
+                if min_length > max_length {
+                    return Err(BertError::InvalidParameters(format!(
+                        "min_length ({}) must not exceed max_length ({})",
+                        min_length, max_length
+                    )));
+                }
+            }
+        }
+        if self.max_new_tokens == Some(0) {
+            return Err(BertError::InvalidParameters(
+                "max_new_tokens must be greater than 0".to_string(),
+            ));
+        }
+        if self.max_length.is_some() && self.max_new_tokens.is_some() {
+            return Err(BertError::InvalidParameters(
+                "max_length and max_new_tokens cannot both be set".to_string(),
+            ));
+        }
+        if let Some(temperature) = self.temperature {
+            if temperature.is_nan() || temperature <= 0.0 {
+                return Err(BertError::InvalidParameters(
+                    "temperature must be greater than 0".to_string(),
+                ));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if top_p.is_nan() || top_p <= 0.0 || top_p > 1.0 {
+                return Err(BertError::InvalidParameters(
+                    "top_p must be in the range (0, 1]".to_string(),
+                ));
+            }
+        }
+        if let Some(repetition_penalty) = self.repetition_penalty {
+            if repetition_penalty.is_nan() || repetition_penalty <= 0.0 {
+                return Err(BertError::InvalidParameters(
+                    "repetition_penalty must be greater than 0".to_string(),
+                ));
+            }
+        }
+        if let Some(diversity_penalty) = self.diversity_penalty {
+            if diversity_penalty.is_nan() || diversity_penalty < 0.0 {
+                return Err(BertError::InvalidParameters(
+                    "diversity_penalty must not be negative".to_string(),
+                ));
+            }
+        }
+        if let Some(length_penalty) = self.length_penalty {
+            if !length_penalty.is_finite() {
+                return Err(BertError::InvalidParameters(
+                    "length_penalty must be a finite number".to_string(),
+                ));
+            }
+        }
+        let num_beams = self.num_beams.unwrap_or(1);
+        if num_beams == 0 {
+            return Err(BertError::InvalidParameters(
+                "num_beams must be at least 1".to_string(),
+            ));
+        }
+        if let Some(num_beam_groups) = self.num_beam_groups {
+            if num_beam_groups == 0 || num_beams % num_beam_groups != 0 {
+                return Err(BertError::InvalidParameters(format!(
+                    "num_beams ({}) must be divisible by num_beam_groups ({})",
+                    num_beams, num_beam_groups
+                )));
+            }
+        }
+        if let (Some(n), Some(num_return_sequences)) = (self.n, self.num_return_sequences) {
+            if n != num_return_sequences {
+                return Err(BertError::InvalidParameters(format!(
+                    "n ({}) and num_return_sequences ({}) differ",
+                    n, num_return_sequences
+                )));
+            }
+        }
+        if let Some(num_return_sequences) = self.return_sequences() {
+            if num_return_sequences == 0 {
+                return Err(BertError::InvalidParameters(
+                    "n must be at least 1".to_string(),
+                ));
+            }
+            if self.do_sample == Some(false) && num_return_sequences > num_beams {
+                return Err(BertError::InvalidParameters(format!(
+                    "n ({}) must not exceed num_beams ({}) without sampling",
+                    num_return_sequences, num_beams
+                )));
+            }
+        }
+        if self.timeout_ms == Some(0) {
+            return Err(BertError::InvalidParameters(
+                "timeout_ms must be greater than 0".to_string(),
+            ));
+        }
+        if let Some(logprobs) = self.logprobs {
+            if logprobs > MAX_LOGPROBS {
+                return Err(BertError::InvalidParameters(format!(
+                    "logprobs must not exceed {}",
+                    MAX_LOGPROBS
+                )));
+            }
+        }
+        if self.stop.iter().any(|sequence| sequence.is_empty()) {
+            return Err(BertError::InvalidParameters(
+                "stop sequences must not be empty".to_string(),
+            ));
+        }
+        // rust-bert can mask scores but not add to them, so only bans apply.
+        for (key, bias) in &self.logit_bias {
+            if key.is_empty() || bias.is_nan() || *bias > -MAX_BIAS {
+                return Err(BertError::InvalidParameters(format!(
+                    "logit_bias for {:?} must be -{} or less: only banning tokens is supported",
+                    key, MAX_BIAS
+                )));
+            }
+        }
+        if self.bad_words.iter().any(|word| word.is_empty()) {
+            return Err(BertError::InvalidParameters(
+                "bad_words must not be empty".to_string(),
+            ));
+        }
+        if !self.force_words.is_empty() {
+            if self.force_words.iter().any(|word| word.is_empty()) {
+                return Err(BertError::InvalidParameters(
+                    "force_words must not be empty".to_string(),
+                ));
+            }
+            // A stop sequence could cut a forced phrase off the text.
+            if !self.stop.is_empty() {
+                return Err(BertError::InvalidParameters(
+                    "force_words cannot be combined with stop sequences".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of completions requested, `1` unless `n` or `num_return_sequences` is set.
+    pub fn choices(&self) -> usize {
+        usize::from(self.return_sequences().unwrap_or(1))
+    }
+
+    fn return_sequences(&self) -> Option<u16> {
+        self.n.or(self.num_return_sequences)
+    }
+
+    /// Whether these parameters ask for more than one beam, which cannot be streamed.
+    pub fn beam_search(&self) -> bool {
+        self.num_beams.unwrap_or(1) > 1 || self.num_beam_groups.unwrap_or(1) > 1
+    }
+
+    pub fn generate_options<'a>(&self) -> GenerateOptions<'a> {
+        GenerateOptions {
+            min_length: self.min_length.map(i64::from),
+            max_length: self.max_length.map(i64::from),
+            max_new_tokens: self.max_new_tokens.map(i64::from),
+            do_sample: self.do_sample,
+            early_stopping: self.early_stopping,
+            num_beams: self.num_beams.map(i64::from),
+            num_beam_groups: self.num_beam_groups.map(i64::from),
+            num_return_sequences: self.return_sequences().map(i64::from),
+            temperature: self.temperature.map(f64::from),
+            top_k: self.top_k.map(i64::from),
+            top_p: self.top_p.map(f64::from),
+            repetition_penalty: self.repetition_penalty.map(f64::from),
+            length_penalty: self.length_penalty.map(f64::from),
+            no_repeat_ngram_size: self.no_repeat_ngram_size.map(i64::from),
+            diversity_penalty: self.diversity_penalty.map(f64::from),
+            output_scores: true,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(GenerationParams::default().validate().is_ok());
+        let params = GenerationParams {
+            max_length: Some(10),
+            max_new_tokens: Some(10),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+        let params = GenerationParams {
+            max_length: Some(10),
+            min_length: Some(20),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+        let params = GenerationParams {
+            top_p: Some(4.0),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+        let params = GenerationParams {
+            diversity_penalty: Some(f32::NAN),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+        let params = GenerationParams {
+            length_penalty: Some(f32::INFINITY),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+        let params = GenerationParams {
+            num_beams: Some(4),
+            num_beam_groups: Some(3),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+        let params = GenerationParams {
+            n: Some(3),
+            num_return_sequences: Some(2),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+        let params = GenerationParams {
+            num_return_sequences: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(params.choices(), 3);
+        let params = GenerationParams {
+            n: Some(0),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+        let params = GenerationParams {
+            logprobs: Some(21),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+        let params = GenerationParams {
+            force_words: vec!["Paris".to_string()],
+            stop: vec!["\n".to_string()],
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+        let params = GenerationParams {
+            logit_bias: BTreeMap::from([("50256".to_string(), 5.0)]),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+        let params = GenerationParams {
+            logit_bias: BTreeMap::from([("50256".to_string(), f32::NAN)]),
+            ..Default::default()
+        };
+        assert!(params.validate().is_err());
+        let params = GenerationParams {
+            num_beams: Some(3),
+            logit_bias: BTreeMap::from([("50256".to_string(), -100.0)]),
+            ..Default::default()
+        };
+        assert!(params.validate().is_ok());
+    }
+}
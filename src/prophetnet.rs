@@ -1,21 +1,29 @@
-use async_trait::async_trait;
-use rust_bert::pipelines::common::ModelType;
-use rust_bert::pipelines::text_generation::{TextGenerationConfig, TextGenerationModel};
+use rust_bert::pipelines::common::TokenizerOption;
+use rust_bert::pipelines::generation_utils::{
+    GenerateConfig, GenerateOptions, GeneratedIndicesOutput, LanguageGenerator,
+};
 use rust_bert::prophetnet::{
-    ProphetNetConfigResources, ProphetNetModelResources, ProphetNetVocabResources,
+    ProphetNetConditionalGenerator, ProphetNetConfig, ProphetNetConfigResources,
+    ProphetNetModelResources, ProphetNetVocabResources,
 };
-use rust_bert::resources::RemoteResource;
-use std::error;
+use rust_bert::resources::{RemoteResource, ResourceProvider};
+use rust_bert::Config;
 use tch::Device;
 
-use crate::ai::AI;
+use crate::error::BertError;
+use crate::generation::{revision, Defaults, Generator};
 
 pub struct ProphetNet {
-    model: TextGenerationModel,
+    model: ProphetNetConditionalGenerator,
+    vocab_size: i64,
+    eos_token_id: i64,
+    max_positions: i64,
+    defaults: Defaults,
+    revision: String,
 }
 
 impl ProphetNet {
-    pub fn new(token_max_length: u16, temperature: f32, top_p: f32) -> Self {
+    pub fn new(token_max_length: u16, temperature: f32, top_p: f32) -> Result<Self, BertError> {
         let config_resource = Box::new(RemoteResource::from_pretrained(
             ProphetNetConfigResources::PROPHETNET_LARGE_UNCASED,
         ));
@@ -28,8 +36,9 @@ impl ProphetNet {
         let model_resource = Box::new(RemoteResource::from_pretrained(
             ProphetNetModelResources::PROPHETNET_LARGE_UNCASED,
         ));
-        let generate_config = TextGenerationConfig {
-            model_type: ModelType::ProphetNet,
+        let config = ProphetNetConfig::from_file(config_resource.get_local_path()?);
+        let revision = revision(&model_resource, &model_resource.get_local_path()?);
+        let generate_config = GenerateConfig {
             model_resource,
             config_resource,
             vocab_resource,
@@ -46,57 +55,87 @@ impl ProphetNet {
             ..Default::default()
         };
 
-        let model = std::thread::spawn(move || {
-            let mut model = TextGenerationModel::new(generate_config).unwrap();
-            model.set_device(Device::cuda_if_available());
-            model
+        let defaults = Defaults::from(&generate_config);
+        let model = ProphetNetConditionalGenerator::new(generate_config)?;
+        Ok(ProphetNet {
+            model,
+            vocab_size: config.vocab_size,
+            eos_token_id: config.eos_token_id,
+            max_positions: config.max_position_embeddings,
+            defaults,
+            revision,
         })
-        .join()
-        .expect("Thread panicked");
-        ProphetNet { model }
     }
 }
 
-unsafe impl Send for ProphetNet {}
+impl Generator for ProphetNet {
+    fn generate_indices(
+        &self,
+        prompts: &[String],
+        options: GenerateOptions,
+    ) -> Vec<GeneratedIndicesOutput> {
+        self.model.generate_indices(Some(prompts), Some(options))
+    }
+
+    fn tokenizer(&self) -> &TokenizerOption {
+        self.model.get_tokenizer()
+    }
 
-unsafe impl Sync for ProphetNet {}
+    fn vocab_size(&self) -> i64 {
+        self.vocab_size
+    }
 
-#[async_trait]
-impl AI for ProphetNet {
-    async fn response(
-        &self,
-        context: String,
-        _token_max_length: u16,
-        _temperature: f32,
-        _top_p: f32,
-        _stop_sequence: Option<String>,
-    ) -> Result<String, Box<dyn error::Error>> {
-        let output = self.model.generate(&[context.to_string()], None);
-        let response = output[0].to_string();
-        let response = response.replace(context.as_str(), "");
-        Ok(response)
+    fn eos_token_id(&self) -> i64 {
+        self.eos_token_id
+    }
+
+    fn max_positions(&self) -> i64 {
+        self.max_positions
+    }
+
+    fn defaults(&self) -> &Defaults {
+        &self.defaults
+    }
+
+    fn is_encoder_decoder(&self) -> bool {
+        true
     }
 
-    fn name(&self) -> String {
-        "prophetnet".to_string()
+    fn revision(&self) -> &str {
+        &self.revision
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ai::AI;
+    use crate::batch::BatchConfig;
+    use crate::params::GenerationParams;
+    use crate::worker::Worker;
 
     #[tokio::test]
     async fn test_response() {
-        let ai = ProphetNet::new(42, 1.1, 0.9);
+        let ai = Worker::spawn("prophetnet", BatchConfig::default(), || {
+            ProphetNet::new(42, 1.1, 0.9)
+        })
+        .unwrap();
         let context = "Lots of Tesla cars to deliver before year end! Your support in taking delivery is much appreciated.".to_string();
+        let params = GenerationParams {
+            max_length: Some(42),
+            temperature: Some(1.1),
+            top_p: Some(0.9),
+            ..Default::default()
+        };
         let output = ai
-            .response(context.to_string(), 42, 1.1, 0.9, None)
+            .response(context.to_string(), params)
             .await
-            .unwrap();
-        println!("{}", output);
-        assert_ne!(output, context);
-        assert_ne!(output.len(), 0);
-        assert!(output.len() > 10);
+            .unwrap()
+            .choices
+            .remove(0);
+        println!("{}", output.text);
+        assert_ne!(output.text, context);
+        assert_ne!(output.text.len(), 0);
+        assert!(output.text.len() > 10);
     }
 }
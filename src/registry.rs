@@ -0,0 +1,271 @@
+use crate::ai::AI;
+use crate::batch::BatchConfig;
+use crate::error::BertError;
+use crate::worker::Worker;
+use crate::{bart, gpt2, gptneo, gptneo1, gptneo2, m2m100, mbart, prophetnet};
+
+/// Creates a backend from `token_max_length`, `temperature`, `top_p` and its batching.
+pub type ModelLoader =
+    Box<dyn Fn(u16, f32, f32, BatchConfig) -> Result<Box<dyn AI>, BertError> + Send + Sync>;
+
+pub struct ModelEntry {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub description: String,
+    /// Approximate memory needed once loaded, used for the LRU memory budget.
+    pub memory_mb: u64,
+    loader: ModelLoader,
+}
+
+impl ModelEntry {
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || self
+                .aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Available `AI` backends, looked up by name or alias.
+pub struct ModelRegistry {
+    entries: Vec<ModelEntry>,
+}
+
+impl ModelRegistry {
+    /// Creates a registry without any backends.
+    pub fn empty() -> Self {
+        ModelRegistry {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Adds a backend, replacing any backend registered under the same name.
+    pub fn register<F>(
+        &mut self,
+        name: &str,
+        aliases: &[&str],
+        description: &str,
+        memory_mb: u64,
+        loader: F,
+    ) where
+        F: Fn(u16, f32, f32, BatchConfig) -> Result<Box<dyn AI>, BertError> + Send + Sync + 'static,
+    {
+        self.entries.retain(|entry| entry.name != name);
+        self.entries.push(ModelEntry {
+            name: name.to_string(),
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+            description: description.to_string(),
+            memory_mb,
+            loader: Box::new(loader),
+        });
+    }
+
+    pub fn models(&self) -> &[ModelEntry] {
+        &self.entries
+    }
+
+    pub fn resolve(&self, name: &str) -> Result<&ModelEntry, BertError> {
+        self.entries
+            .iter()
+            .find(|entry| entry.matches(name))
+            .ok_or_else(|| BertError::UnknownModel {
+                name: name.to_string(),
+                suggestions: self.suggestions(name),
+            })
+    }
+
+    pub fn load(
+        &self,
+        name: &str,
+        token_max_length: u16,
+        temperature: f32,
+        top_p: f32,
+        batch: BatchConfig,
+    ) -> Result<Box<dyn AI>, BertError> {
+        let entry = self.resolve(name)?;
+        (entry.loader)(token_max_length, temperature, top_p, batch)
+    }
+
+    /// Names within a small edit distance of `name`, or every name if none are close.
+    fn suggestions(&self, name: &str) -> Vec<String> {
+        let name = name.to_lowercase();
+        let mut close: Vec<(usize, &str)> = self
+            .entries
+            .iter()
+            .filter_map(|entry| {
+                std::iter::once(&entry.name)
+                    .chain(entry.aliases.iter())
+                    .map(|candidate| edit_distance(&name, &candidate.to_lowercase()))
+                    .min()
+                    .filter(|distance| *distance <= 2)
+                    .map(|distance| (distance, entry.name.as_str()))
+            })
+            .collect();
+        close.sort();
+        if close.is_empty() {
+            self.entries
+                .iter()
+                .map(|entry| entry.name.clone())
+                .collect()
+        } else {
+            close.iter().map(|(_, name)| name.to_string()).collect()
+        }
+    }
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        let mut registry = ModelRegistry::empty();
+        registry.register(
+            "bart",
+            &["bart-cnn"],
+            "BART fine-tuned on CNN/DailyMail, sampled (see summarize)",
+            1600,
+            |length, temperature, top_p, batch| {
+                Ok(Box::new(Worker::spawn("bart", batch, move || {
+                    bart::Bart::new(length, temperature, top_p)
+                })?))
+            },
+        );
+        registry.register(
+            "mbart",
+            &["mbart50"],
+            "mBART-50 many-to-many",
+            2450,
+            |length, temperature, top_p, batch| {
+                Ok(Box::new(Worker::spawn("mbart", batch, move || {
+                    mbart::MBart::new(length, temperature, top_p)
+                })?))
+            },
+        );
+        registry.register(
+            "m2m100",
+            &["m2m100-1.2b"],
+            "M2M100 1.2B multilingual",
+            4800,
+            |length, temperature, top_p, batch| {
+                Ok(Box::new(Worker::spawn("m2m100", batch, move || {
+                    m2m100::M2M100::new(length, temperature, top_p)
+                })?))
+            },
+        );
+        registry.register(
+            "gpt2",
+            &["gpt2-small"],
+            "GPT-2 124M",
+            500,
+            |length, temperature, top_p, batch| {
+                Ok(Box::new(Worker::spawn("gpt2", batch, move || {
+                    gpt2::GPT2::new(length, temperature, top_p)
+                })?))
+            },
+        );
+        registry.register(
+            "gptneo",
+            &["gpt-neo-125m"],
+            "GPT-Neo 125M",
+            500,
+            |length, temperature, top_p, batch| {
+                Ok(Box::new(Worker::spawn("gptneo", batch, move || {
+                    gptneo::GPTNeo::new(length, temperature, top_p)
+                })?))
+            },
+        );
+        registry.register(
+            "gptneo1",
+            &["gpt-neo-1.3b"],
+            "GPT-Neo 1.3B",
+            5300,
+            |length, temperature, top_p, batch| {
+                Ok(Box::new(Worker::spawn("gptneo1", batch, move || {
+                    gptneo1::GPTNeo1::new(length, temperature, top_p)
+                })?))
+            },
+        );
+        registry.register(
+            "gptneo2",
+            &["gpt-neo-2.7b"],
+            "GPT-Neo 2.7B",
+            10700,
+            |length, temperature, top_p, batch| {
+                Ok(Box::new(Worker::spawn("gptneo2", batch, move || {
+                    gptneo2::GPTNeo2::new(length, temperature, top_p)
+                })?))
+            },
+        );
+        registry.register(
+            "prophetnet",
+            &["prophetnet-large"],
+            "ProphetNet large uncased",
+            1600,
+            |length, temperature, top_p, batch| {
+                Ok(Box::new(Worker::spawn("prophetnet", batch, move || {
+                    prophetnet::ProphetNet::new(length, temperature, top_p)
+                })?))
+            },
+        );
+        registry
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::tests::Echo;
+
+    #[test]
+    fn test_resolve() {
+        let registry = ModelRegistry::default();
+        assert_eq!(registry.resolve("gpt2").unwrap().name, "gpt2");
+        assert_eq!(registry.resolve("GPT-Neo-1.3B").unwrap().name, "gptneo1");
+        match registry.resolve("gtp2") {
+            Err(BertError::UnknownModel { suggestions, .. }) => {
+                assert_eq!(suggestions[0], "gpt2")
+            }
+            _ => panic!("expected unknown model"),
+        }
+        match registry.resolve("llama") {
+            Err(BertError::UnknownModel { suggestions, .. }) => {
+                assert_eq!(suggestions.len(), registry.models().len())
+            }
+            _ => panic!("expected unknown model"),
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let mut registry = ModelRegistry::empty();
+        registry.register("echo", &["parrot"], "Echoes the prompt", 0, |_, _, _, _| {
+            Ok(Box::new(Echo))
+        });
+        let ai = registry
+            .load("parrot", 10, 1.0, 0.9, BatchConfig::default())
+            .unwrap();
+        assert_eq!(ai.name(), "echo");
+        assert!(registry
+            .load("gpt2", 10, 1.0, 0.9, BatchConfig::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("gpt2", "gpt2"), 0);
+        assert_eq!(edit_distance("gtp2", "gpt2"), 2);
+        assert_eq!(edit_distance("bart", "mbart"), 1);
+    }
+}
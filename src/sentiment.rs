@@ -0,0 +1,78 @@
+use rocket_okapi::okapi::schemars;
+use rocket_okapi::okapi::schemars::JsonSchema;
+use rust_bert::pipelines::sentiment::{SentimentConfig, SentimentModel, SentimentPolarity};
+use serde::{Deserialize, Serialize};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::error::BertError;
+use crate::SentimentResponse;
+
+/// Texts scored per forward pass.
+const TEXTS_PER_PASS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Polarity {
+    Positive,
+    Negative,
+}
+
+/// Binary sentiment with DistilBERT fine-tuned on SST-2.
+pub struct SentimentAnalyzer {
+    model: SentimentModel,
+}
+
+impl SentimentAnalyzer {
+    pub fn new() -> Result<Self, BertError> {
+        let model = SentimentModel::new(SentimentConfig::default())?;
+        Ok(SentimentAnalyzer { model })
+    }
+
+    /// The polarity of every text, with the model's confidence in it.
+    pub fn analyze(&self, texts: &[String]) -> Result<Vec<SentimentResponse>, BertError> {
+        if texts.iter().any(|text| text.trim().is_empty()) {
+            return Err(BertError::InvalidParameters(
+                "texts must not be empty".to_string(),
+            ));
+        }
+        let mut responses = Vec::with_capacity(texts.len());
+        for chunk in texts.chunks(TEXTS_PER_PASS) {
+            let inputs: Vec<&str> = chunk.iter().map(String::as_str).collect();
+            let sentiments = panic::catch_unwind(AssertUnwindSafe(|| self.model.predict(&inputs)))
+                .map_err(BertError::from_panic)?;
+            responses.extend(chunk.iter().zip(sentiments).map(|(text, sentiment)| {
+                SentimentResponse {
+                    text: text.clone(),
+                    polarity: match sentiment.polarity {
+                        SentimentPolarity::Positive => Polarity::Positive,
+                        SentimentPolarity::Negative => Polarity::Negative,
+                    },
+                    score: sentiment.score as f32,
+                }
+            }));
+        }
+        Ok(responses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analyze() {
+        let analyzer = SentimentAnalyzer::new().unwrap();
+        let texts = vec![
+            "I love how quickly support fixed my problem.".to_string(),
+            "The package arrived late and broken.".to_string(),
+        ];
+        let responses = analyzer.analyze(&texts).unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].polarity, Polarity::Positive);
+        assert_eq!(responses[1].polarity, Polarity::Negative);
+        assert!(responses
+            .iter()
+            .all(|response| (0.5..=1.0).contains(&response.score)));
+        assert!(analyzer.analyze(&[String::new()]).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use rust_bert::bart::{
+    BartConfigResources, BartMergesResources, BartModelResources, BartVocabResources,
+};
+use rust_bert::pipelines::common::ModelType;
+use rust_bert::pipelines::summarization::{SummarizationConfig, SummarizationModel};
+use rust_bert::resources::RemoteResource;
+use std::panic::{self, AssertUnwindSafe};
+use tch::Device;
+
+use crate::error::BertError;
+
+/// Texts summarized per forward pass. Beam search keeps `NUM_BEAMS` hypotheses
+/// for each of them.
+const TEXTS_PER_PASS: usize = 8;
+
+const NUM_BEAMS: i64 = 4;
+
+/// Abstractive summaries with BART fine-tuned on CNN/DailyMail, decoded by beam
+/// search with the settings the checkpoint was evaluated with.
+pub struct Summarizer {
+    model: SummarizationModel,
+}
+
+impl Summarizer {
+    pub fn new() -> Result<Self, BertError> {
+        let config = SummarizationConfig {
+            model_type: ModelType::Bart,
+            model_resource: Box::new(RemoteResource::from_pretrained(
+                BartModelResources::BART_CNN,
+            )),
+            config_resource: Box::new(RemoteResource::from_pretrained(
+                BartConfigResources::BART_CNN,
+            )),
+            vocab_resource: Box::new(RemoteResource::from_pretrained(
+                BartVocabResources::BART_CNN,
+            )),
+            merges_resource: Box::new(RemoteResource::from_pretrained(
+                BartMergesResources::BART_CNN,
+            )),
+            min_length: 56,
+            max_length: 142,
+            do_sample: false,
+            early_stopping: true,
+            num_beams: NUM_BEAMS,
+            length_penalty: 2.0,
+            no_repeat_ngram_size: 3,
+            num_return_sequences: 1,
+            device: Device::cuda_if_available(),
+            ..Default::default()
+        };
+        let model = SummarizationModel::new(config)?;
+        Ok(Summarizer { model })
+    }
+
+    /// One summary per text, in order.
+    pub fn summarize(&self, texts: &[String]) -> Result<Vec<String>, BertError> {
+        if texts.iter().any(|text| text.trim().is_empty()) {
+            return Err(BertError::InvalidParameters(
+                "texts must not be empty".to_string(),
+            ));
+        }
+        let mut summaries = Vec::with_capacity(texts.len());
+        for chunk in texts.chunks(TEXTS_PER_PASS) {
+            let chunk_summaries =
+                panic::catch_unwind(AssertUnwindSafe(|| self.model.summarize(chunk)))
+                    .map_err(BertError::from_panic)?;
+            summaries.extend(
+                chunk_summaries
+                    .into_iter()
+                    .map(|summary| summary.trim().to_string()),
+            );
+        }
+        Ok(summaries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        let summarizer = Summarizer::new().unwrap();
+        let text = "In findings published Tuesday in Cornell University's arXiv by a team of scientists \
+from the University of Montreal and a separate report published Wednesday in Nature Astronomy by a team \
+from University College London (UCL), the presence of water vapour was confirmed in the atmosphere of K2-18b, \
+a planet circling a star in the constellation Leo. This is the first such discovery in a planet in its star's \
+habitable zone - not too hot and not too cold for liquid water to exist. The Montreal team, led by Björn Benneke, \
+used data from the NASA's Hubble telescope to assess changes in the light coming from K2-18b's star as the planet \
+passed between it and Earth. They found that certain wavelengths of light, which are usually absorbed by water, \
+weakened when the planet was in the way, indicating not only does K2-18b have an atmosphere, but the atmosphere \
+contains water in vapour form.";
+        let summaries = summarizer.summarize(&[text.to_string()]).unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert!(!summaries[0].is_empty());
+        assert!(summaries[0].len() < text.len());
+
+        assert!(summarizer.summarize(&[" ".to_string()]).is_err());
+    }
+}
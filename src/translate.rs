@@ -0,0 +1,180 @@
+use rust_bert::m2m_100::{
+    M2M100ConfigResources, M2M100MergesResources, M2M100ModelResources, M2M100SourceLanguages,
+    M2M100VocabResources,
+};
+use rust_bert::mbart::{
+    MBartConfigResources, MBartModelResources, MBartSourceLanguages, MBartVocabResources,
+};
+use rust_bert::pipelines::common::ModelType;
+use rust_bert::pipelines::translation::{Language, TranslationConfig, TranslationModel};
+use rust_bert::resources::RemoteResource;
+use std::panic::{self, AssertUnwindSafe};
+use tch::Device;
+
+use crate::error::BertError;
+
+/// Models that translate, by their registry name. The first is the default.
+pub const TRANSLATION_MODELS: [&str; 2] = ["mbart", "m2m100"];
+
+/// Texts translated per forward pass.
+const TEXTS_PER_PASS: usize = 8;
+
+/// Languages `model` translates from and into. Both models translate between
+/// any two of their languages.
+fn languages(model: &str) -> Result<&'static [Language], BertError> {
+    match model {
+        "mbart" => Ok(&MBartSourceLanguages::MBART50_MANY_TO_MANY),
+        "m2m100" => Ok(&M2M100SourceLanguages::M2M100_1_2B),
+        _ => Err(BertError::InvalidParameters(format!(
+            "{} does not translate, use one of {}",
+            model,
+            TRANSLATION_MODELS.join(", ")
+        ))),
+    }
+}
+
+/// The language `model` supports with the ISO 639-1 or 639-3 `code`.
+fn language(model: &str, code: &str) -> Result<Language, BertError> {
+    let languages = languages(model)?;
+    let code = code.to_lowercase();
+    languages
+        .iter()
+        .find(|language| {
+            language.get_iso_639_1_code() == code || language.get_iso_639_3_code() == code
+        })
+        .copied()
+        .ok_or_else(|| {
+            let mut codes: Vec<&str> = languages
+                .iter()
+                .map(|language| language.get_iso_639_1_code())
+                .collect();
+            codes.sort_unstable();
+            BertError::InvalidParameters(format!(
+                "{} does not support language {:?}, use one of {}",
+                model,
+                code,
+                codes.join(", ")
+            ))
+        })
+}
+
+/// Translation with mBART-50 or M2M100, with explicit source and target
+/// languages. Loads its own copy of the model, next to the generator.
+pub struct Translator {
+    model: TranslationModel,
+    name: String,
+}
+
+impl Translator {
+    pub fn new(model: &str) -> Result<Self, BertError> {
+        let languages = languages(model)?;
+        let device = Device::cuda_if_available();
+        let config = match model {
+            "mbart" => TranslationConfig::new(
+                ModelType::MBart,
+                Box::new(RemoteResource::from_pretrained(
+                    MBartModelResources::MBART50_MANY_TO_MANY,
+                )),
+                Box::new(RemoteResource::from_pretrained(
+                    MBartConfigResources::MBART50_MANY_TO_MANY,
+                )),
+                Box::new(RemoteResource::from_pretrained(
+                    MBartVocabResources::MBART50_MANY_TO_MANY,
+                )),
+                Box::new(RemoteResource::from_pretrained(
+                    MBartVocabResources::MBART50_MANY_TO_MANY,
+                )),
+                languages,
+                languages,
+                device,
+            ),
+            _ => TranslationConfig::new(
+                ModelType::M2M100,
+                Box::new(RemoteResource::from_pretrained(
+                    M2M100ModelResources::M2M100_1_2B,
+                )),
+                Box::new(RemoteResource::from_pretrained(
+                    M2M100ConfigResources::M2M100_1_2B,
+                )),
+                Box::new(RemoteResource::from_pretrained(
+                    M2M100VocabResources::M2M100_1_2B,
+                )),
+                Box::new(RemoteResource::from_pretrained(
+                    M2M100MergesResources::M2M100_1_2B,
+                )),
+                languages,
+                languages,
+                device,
+            ),
+        };
+        let model_name = model.to_string();
+        let model = TranslationModel::new(config)?;
+        Ok(Translator {
+            model,
+            name: model_name,
+        })
+    }
+
+    /// Fails unless `model` translates between the two languages, without
+    /// loading it.
+    pub fn check(model: &str, source_lang: &str, target_lang: &str) -> Result<(), BertError> {
+        language(model, source_lang)?;
+        language(model, target_lang)?;
+        Ok(())
+    }
+
+    /// Translates every text from `source_lang` into `target_lang`, both
+    /// language codes like `de` or `en`.
+    pub fn translate(
+        &self,
+        texts: &[String],
+        source_lang: &str,
+        target_lang: &str,
+    ) -> Result<Vec<String>, BertError> {
+        let source = language(&self.name, source_lang)?;
+        let target = language(&self.name, target_lang)?;
+        if texts.iter().any(|text| text.trim().is_empty()) {
+            return Err(BertError::InvalidParameters(
+                "texts must not be empty".to_string(),
+            ));
+        }
+        let mut translations = Vec::with_capacity(texts.len());
+        for chunk in texts.chunks(TEXTS_PER_PASS) {
+            let chunk_translations = panic::catch_unwind(AssertUnwindSafe(|| {
+                self.model.translate(chunk, source, target)
+            }))
+            .map_err(BertError::from_panic)?
+            .map_err(|err| BertError::Inference(err.to_string()))?;
+            translations.extend(
+                chunk_translations
+                    .into_iter()
+                    .map(|translation| translation.trim().to_string()),
+            );
+        }
+        Ok(translations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_language() {
+        assert_eq!(language("mbart", "de").unwrap(), Language::German);
+        assert_eq!(language("m2m100", "EN").unwrap(), Language::English);
+        assert_eq!(language("m2m100", "deu").unwrap(), Language::German);
+        assert!(language("mbart", "xx").is_err());
+        assert!(language("gpt2", "en").is_err());
+    }
+
+    #[test]
+    fn test_translate() {
+        let translator = Translator::new("mbart").unwrap();
+        let texts = vec!["Das Wetter ist heute schön.".to_string()];
+        let translations = translator.translate(&texts, "de", "en").unwrap();
+        assert_eq!(translations.len(), 1);
+        assert!(translations[0].to_lowercase().contains("weather"));
+        assert!(translator.translate(&texts, "de", "xx").is_err());
+    }
+}
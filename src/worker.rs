@@ -0,0 +1,313 @@
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::oneshot;
+
+use crate::ai::AI;
+use crate::batch::{BatchConfig, BatchMetrics, Batcher};
+use crate::error::BertError;
+use crate::generation::{self, Generation, GenerationStream, Generator, Interrupt};
+use crate::params::GenerationParams;
+
+struct Job {
+    context: String,
+    params: GenerationParams,
+    reply: oneshot::Sender<Result<Generation, BertError>>,
+    /// Set for streaming requests, which are never batched.
+    deltas: Option<UnboundedSender<String>>,
+    deadline: Option<Instant>,
+}
+
+impl Job {
+    fn new(
+        context: String,
+        params: GenerationParams,
+        reply: oneshot::Sender<Result<Generation, BertError>>,
+        deltas: Option<UnboundedSender<String>>,
+    ) -> Self {
+        let deadline = params
+            .timeout_ms
+            .map(|timeout_ms| Instant::now() + Duration::from_millis(timeout_ms));
+        Job {
+            context,
+            params,
+            reply,
+            deltas,
+            deadline,
+        }
+    }
+}
+
+/// Work for the inference thread. Checks run alone, ahead of generation.
+enum Task {
+    Generate(Job),
+    Check {
+        context: String,
+        params: GenerationParams,
+        reply: oneshot::Sender<Result<(), BertError>>,
+    },
+}
+
+/// Owns a model on a dedicated inference thread. Requests are sent to the
+/// thread over a channel and batched with concurrent requests that share
+/// their parameters, so the tch model is never shared between threads and
+/// generation never blocks the async runtime.
+pub(crate) struct Worker {
+    name: String,
+    revision: String,
+    sender: mpsc::Sender<Task>,
+    metrics: Arc<Mutex<BatchMetrics>>,
+    scorer_loaded: Arc<AtomicBool>,
+}
+
+impl Worker {
+    /// Starts the inference thread and waits until `load` has built the model on it.
+    pub fn spawn<G, F>(name: &str, batch: BatchConfig, load: F) -> Result<Self, BertError>
+    where
+        G: Generator + 'static,
+        F: FnOnce() -> Result<G, BertError> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<Task>();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        let metrics = Arc::new(Mutex::new(BatchMetrics::default()));
+        let worker_metrics = metrics.clone();
+        let scorer_loaded = Arc::new(AtomicBool::new(false));
+        let worker_scorer_loaded = scorer_loaded.clone();
+        thread::Builder::new()
+            .name(format!("bert-{}", name))
+            .spawn(move || {
+                let generator = match load() {
+                    Ok(generator) => {
+                        let _ = ready_sender.send(Ok(generator.revision().to_string()));
+                        generator
+                    }
+                    Err(err) => {
+                        let _ = ready_sender.send(Err(err));
+                        return;
+                    }
+                };
+                let mut batcher = Batcher::new(receiver, batch);
+                let compatible = |a: &Task, b: &Task| match (a, b) {
+                    // A batch consumes random numbers differently than a single
+                    // request, so seeded requests run alone to stay reproducible.
+                    (Task::Generate(a), Task::Generate(b)) => {
+                        a.deltas.is_none()
+                            && b.deltas.is_none()
+                            && a.params.seed.is_none()
+                            && a.params == b.params
+                    }
+                    _ => false,
+                };
+                while let Some(tasks) = batcher.next_batch(compatible) {
+                    let mut jobs = Vec::with_capacity(tasks.len());
+                    for task in tasks {
+                        match task {
+                            Task::Generate(job) => jobs.push(job),
+                            Task::Check {
+                                context,
+                                params,
+                                reply,
+                            } => {
+                                let _ =
+                                    reply.send(generation::check(&generator, &context, &params));
+                            }
+                        }
+                    }
+                    // Requests whose caller went away while queued are dropped.
+                    jobs.retain(|job| !job.reply.is_closed());
+                    if jobs.is_empty() {
+                        continue;
+                    }
+                    let deadline = jobs.iter().filter_map(|job| job.deadline).max();
+                    if let Some(deltas) = jobs[0].deltas.take() {
+                        let job = jobs.remove(0);
+                        let on_delta = |delta: String| {
+                            let _ = deltas.send(delta);
+                        };
+                        let cancelled = || job.reply.is_closed();
+                        let interrupt = Interrupt {
+                            deadline,
+                            cancelled: &cancelled,
+                        };
+                        let result = generation::generate_streaming(
+                            &generator,
+                            &job.context,
+                            &job.params,
+                            &interrupt,
+                            &on_delta,
+                        );
+                        if let Ok(mut metrics) = worker_metrics.lock() {
+                            metrics.record(1);
+                        }
+                        worker_scorer_loaded.store(generator.scorer_loaded(), Ordering::Relaxed);
+                        let _ = job.reply.send(result);
+                        continue;
+                    }
+                    let params = jobs[0].params.clone();
+                    let (contexts, replies): (Vec<String>, Vec<_>) =
+                        jobs.into_iter().map(|job| (job.context, job.reply)).unzip();
+                    // A batch is abandoned only once every caller has gone away.
+                    let cancelled = || replies.iter().all(|reply| reply.is_closed());
+                    let interrupt = Interrupt {
+                        deadline,
+                        cancelled: &cancelled,
+                    };
+                    let results = generation::generate(&generator, &contexts, &params, &interrupt);
+                    if let Ok(mut metrics) = worker_metrics.lock() {
+                        metrics.record(contexts.len());
+                    }
+                    worker_scorer_loaded.store(generator.scorer_loaded(), Ordering::Relaxed);
+                    for (reply, result) in replies.into_iter().zip(results) {
+                        let _ = reply.send(result);
+                    }
+                }
+            })
+            .map_err(|err| BertError::ModelLoad(err.to_string()))?;
+        let revision = ready_receiver
+            .recv()
+            .map_err(|_| BertError::ModelLoad("Thread panicked".to_string()))??;
+        Ok(Worker {
+            name: name.to_string(),
+            revision,
+            sender,
+            metrics,
+            scorer_loaded,
+        })
+    }
+
+    fn submit(&self, job: Job) -> Result<(), BertError> {
+        self.sender
+            .send(Task::Generate(job))
+            .map_err(|_| self.stopped())
+    }
+
+    fn stopped(&self) -> BertError {
+        BertError::Inference(format!("{} worker stopped", self.name))
+    }
+}
+
+#[async_trait]
+impl AI for Worker {
+    async fn response(
+        &self,
+        context: String,
+        params: GenerationParams,
+    ) -> Result<Generation, BertError> {
+        let (reply, response) = oneshot::channel();
+        self.submit(Job::new(context, params, reply, None))?;
+        response.await.map_err(|_| self.stopped())?
+    }
+
+    async fn stream(
+        &self,
+        context: String,
+        params: GenerationParams,
+    ) -> Result<GenerationStream, BertError> {
+        let (reply, result) = oneshot::channel();
+        let (deltas, receiver) = unbounded_channel();
+        self.submit(Job::new(context, params, reply, Some(deltas)))?;
+        Ok(GenerationStream::new(
+            self.name.clone(),
+            self.revision(),
+            receiver,
+            result,
+        ))
+    }
+
+    async fn check(&self, context: String, params: GenerationParams) -> Result<(), BertError> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(Task::Check {
+                context,
+                params,
+                reply,
+            })
+            .map_err(|_| self.stopped())?;
+        response.await.map_err(|_| self.stopped())?
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn revision(&self) -> Option<String> {
+        Some(self.revision.clone())
+    }
+
+    fn batch_metrics(&self) -> Option<BatchMetrics> {
+        self.metrics.lock().ok().map(|metrics| metrics.clone())
+    }
+
+    fn scorer_loaded(&self) -> bool {
+        self.scorer_loaded.load(Ordering::Relaxed)
+    }
+}
+
+type Task<M> = Box<dyn FnOnce(&M) + Send>;
+
+/// Owns a pipeline other than a generator, like the classifier, on a dedicated
+/// thread and runs tasks against it one at a time.
+pub(crate) struct ModelThread<M> {
+    name: String,
+    sender: mpsc::Sender<Task<M>>,
+}
+
+impl<M: 'static> ModelThread<M> {
+    /// Starts the thread and waits until `load` has built the model on it.
+    pub fn spawn<F>(name: &str, load: F) -> Result<Self, BertError>
+    where
+        F: FnOnce() -> Result<M, BertError> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<Task<M>>();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name(format!("bert-{}", name))
+            .spawn(move || {
+                let model = match load() {
+                    Ok(model) => {
+                        let _ = ready_sender.send(Ok(()));
+                        model
+                    }
+                    Err(err) => {
+                        let _ = ready_sender.send(Err(err));
+                        return;
+                    }
+                };
+                for task in receiver {
+                    task(&model);
+                }
+            })
+            .map_err(|err| BertError::ModelLoad(err.to_string()))?;
+        ready_receiver
+            .recv()
+            .map_err(|_| BertError::ModelLoad("Thread panicked".to_string()))??;
+        Ok(ModelThread {
+            name: name.to_string(),
+            sender,
+        })
+    }
+
+    /// Runs `task` on the model thread. Tasks whose caller has gone away by
+    /// the time they are picked up are skipped.
+    pub async fn run<T, F>(&self, task: F) -> Result<T, BertError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&M) -> Result<T, BertError> + Send + 'static,
+    {
+        let (reply, response) = oneshot::channel();
+        let task: Task<M> = Box::new(move |model| {
+            if !reply.is_closed() {
+                let _ = reply.send(task(model));
+            }
+        });
+        self.sender.send(task).map_err(|_| self.stopped())?;
+        response.await.map_err(|_| self.stopped())?
+    }
+
+    fn stopped(&self) -> BertError {
+        BertError::Inference(format!("{} worker stopped", self.name))
+    }
+}
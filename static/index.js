@@ -2,24 +2,48 @@
 async function generate() {
 
     let data = {
+        model: document.getElementById("model").value,
         context: document.getElementById("context").value,
         top_p: document.getElementById("top_p").value / 100.0,
         temp: document.getElementById("temperature").value / 100.0,
         response_length: 200,
     };
 
-    fetch("/api/completion", {
-        method: "POST",
-        headers: { 'Content-Type': 'application/json' },
-        body: JSON.stringify(data)
-    }).then(response => response.json())
-        .then(data => {
-            const { generated_text, duration } = data;
-            document.getElementById("generated_text").innerHTML = generated_text;
-            document.getElementById("generated_duration").innerHTML = `Generated in ${duration / 1000}s`;
+    const output = document.getElementById("generated_text");
+    const status = document.getElementById("generated_duration");
+    output.textContent = "";
+    status.innerHTML = "";
+
+    const source = new EventSource(`/api/completion/stream?${new URLSearchParams(data)}`);
+    source.addEventListener("delta", event => {
+        output.textContent += JSON.parse(event.data).text;
+    });
+    source.addEventListener("done", event => {
+        source.close();
+        const { choices, model, duration } = JSON.parse(event.data);
+        output.textContent = choices[0].text;
+        status.innerHTML = `Generated by ${model} in ${duration / 1000}s`;
+    });
+    source.addEventListener("error", event => {
+        source.close();
+        if (event.data) {
+            status.textContent = JSON.parse(event.data).message;
         }
-        );
+    });
+}
+
+async function load_models() {
+    fetch("/api/models")
+        .then(response => response.json())
+        .then(models => {
+            const select = document.getElementById("model");
+            models
+                .filter(model => model.kind === "generator")
+                .forEach(model => select.add(new Option(model.name, model.name)));
+        });
 }
 
+load_models();
+
 const generate_button = document.getElementById("generate_button");
 generate_button.addEventListener("click", generate);